@@ -2,6 +2,7 @@ use crate::context::*;
 use crate::controller;
 use crate::settings::initialization_options;
 use crate::settings::record_dynamic_config;
+use crate::text_sync;
 use crate::types::*;
 use crate::util::file_path_to_uri;
 use crate::util::*;
@@ -221,19 +222,19 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                             }),
                             declaration: Some(GotoCapability {
                                 dynamic_registration: Some(false),
-                                link_support: Some(false),
+                                link_support: Some(true),
                             }),
                             definition: Some(GotoCapability {
                                 dynamic_registration: Some(false),
-                                link_support: Some(false),
+                                link_support: Some(true),
                             }),
                             type_definition: Some(GotoCapability {
                                 dynamic_registration: Some(false),
-                                link_support: Some(false),
+                                link_support: Some(true),
                             }),
                             implementation: Some(GotoCapability {
                                 dynamic_registration: Some(false),
-                                link_support: Some(false),
+                                link_support: Some(true),
                             }),
                             code_action: Some(CodeActionClientCapabilities {
                                 dynamic_registration: Some(false),
@@ -286,8 +287,8 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                                         DiagnosticTag::DEPRECATED,
                                     ],
                                 }),
-                                version_support: None,
-                                code_description_support: None,
+                                version_support: Some(true),
+                                code_description_support: Some(true),
                                 data_support: None,
                             }),
                             folding_range: None,
@@ -330,7 +331,14 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                             type_hierarchy: None,
                             inlay_hint: Some(InlayHintClientCapabilities {
                                 dynamic_registration: Some(false),
-                                resolve_support: None,
+                                resolve_support: Some(InlayHintResolveClientCapabilities {
+                                    properties: vec![
+                                        "tooltip".to_string(),
+                                        "label.tooltip".to_string(),
+                                        "label.location".to_string(),
+                                        "label.command".to_string(),
+                                    ],
+                                }),
                             }),
                             diagnostic: None,
                             inline_completion: None,
@@ -355,23 +363,18 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                                 allowed_tags: None,
                             }),
                             stale_request_support: None,
-                            position_encodings: Some(match preferred_offset_encoding {
-                                None | Some(OffsetEncoding::Utf8) => {
-                                    vec![PositionEncodingKind::UTF8, PositionEncodingKind::UTF16]
-                                }
-                                Some(OffsetEncoding::Utf16) => {
-                                    vec![PositionEncodingKind::UTF16, PositionEncodingKind::UTF8]
-                                }
-                            }),
+                            position_encodings: Some(
+                                offset_encodings(*preferred_offset_encoding)
+                                    .into_iter()
+                                    .map(PositionEncodingKind::new)
+                                    .collect(),
+                            ),
                         }),
                         offset_encoding: Some(
-                            match preferred_offset_encoding {
-                                None | Some(OffsetEncoding::Utf8) => ["utf-8", "utf-16"],
-                                Some(OffsetEncoding::Utf16) => ["utf-16", "utf-8"],
-                            }
-                            .iter()
-                            .map(|s| s.to_string())
-                            .collect(),
+                            offset_encodings(*preferred_offset_encoding)
+                                .iter()
+                                .map(|s| s.to_string())
+                                .collect(),
                         ),
                         experimental: ctx
                             .server_config(&meta, server_name)
@@ -391,7 +394,7 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                     process_id: Some(process::id()),
                     root_uri: Some(file_path_to_uri(&roots[0])),
                     root_path: Some(roots[0].clone()),
-                    trace: Some(TraceValue::Off),
+                    trace: Some(ctx.server_trace(server_name)),
                     workspace_folders: Some(vec![WorkspaceFolder {
                         uri: file_path_to_uri(&roots[0]),
                         name: roots[0].clone(),
@@ -425,6 +428,7 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                     .map(|encoding| match encoding {
                         "utf-8" => OffsetEncoding::Utf8,
                         "utf-16" => OffsetEncoding::Utf16,
+                        "utf-32" => OffsetEncoding::Utf32,
                         encoding => {
                             error!(
                                 &to_editor,
@@ -433,27 +437,54 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                         }
                     })
                     .unwrap_or_default();
-                if matches!(
-                    (server.preferred_offset_encoding, server.offset_encoding),
-                    (Some(OffsetEncoding::Utf8), OffsetEncoding::Utf16)) {
+                if let Some(preferred) = server.preferred_offset_encoding.filter(|preferred| *preferred != server.offset_encoding) {
                         warn!(
                             &to_editor,
-                            "Requested offset encoding utf-8 is not supported by {} server, falling back to utf-16",
+                            "Requested offset encoding {} is not supported by {} server, falling back to {}",
+                            offset_encoding_name(preferred),
                             &server.name,
+                            offset_encoding_name(server.offset_encoding),
                         );
                 }
                 server.capabilities = Some(result.capabilities.clone());
                 ctx.notify::<Initialized>(server_id, InitializedParams {});
+                // Only a restarted server has documents to open.
+                text_sync::reopen_documents(server_id, ctx);
             }
         }
         controller::dispatch_pending_editor_requests(ctx)
     });
 }
 
+pub fn offset_encoding_name(offset_encoding: OffsetEncoding) -> &'static str {
+    match offset_encoding {
+        OffsetEncoding::Utf8 => "utf-8",
+        OffsetEncoding::Utf16 => "utf-16",
+        OffsetEncoding::Utf32 => "utf-32",
+    }
+}
+
+/// The offset encodings to offer to the server, most preferred first.
+fn offset_encodings(preferred: Option<OffsetEncoding>) -> Vec<&'static str> {
+    let preferred = preferred.unwrap_or(OffsetEncoding::Utf8);
+    let mut encodings = vec![offset_encoding_name(preferred)];
+    for encoding in [
+        OffsetEncoding::Utf8,
+        OffsetEncoding::Utf16,
+        OffsetEncoding::Utf32,
+    ] {
+        if encoding != preferred {
+            encodings.push(offset_encoding_name(encoding));
+        }
+    }
+    encodings
+}
+
 pub const CAPABILITY_CALL_HIERARCHY: &str = "lsp-incoming-calls, lsp-outgoing-calls";
 pub const CAPABILITY_CODE_ACTIONS: &str = "lsp-code-actions";
 pub const CAPABILITY_CODE_ACTIONS_RESOLVE: &str = "lsp-code-actions-resolve";
 pub const CAPABILITY_CODE_LENS: &str = "lsp-code-lens";
+pub const CAPABILITY_CODE_LENS_RESOLVE: &str = "lsp-code-lens-resolve";
 pub const CAPABILITY_COMPLETION: &str = "lsp-completion (hooked on InsertIdle)";
 pub const CAPABILITY_DEFINITION: &str = "lsp-definition";
 pub const CAPABILITY_DOCUMENT_HIGHLIGHT: &str = "lsp-highlight-references";
@@ -463,6 +494,7 @@ pub const CAPABILITY_FORMATTING: &str = "lsp-formatting";
 pub const CAPABILITY_HOVER: &str = "lsp-hover";
 pub const CAPABILITY_IMPLEMENTATION: &str = "lsp-implementation";
 pub const CAPABILITY_INLAY_HINTS: &str = "lsp-inlay-hints";
+pub const CAPABILITY_INLAY_HINTS_RESOLVE: &str = "lsp-inlay-hints-resolve";
 pub const CAPABILITY_RANGE_FORMATTING: &str = "lsp-range-formatting";
 pub const CAPABILITY_REFERENCES: &str = "lsp-references";
 pub const CAPABILITY_RENAME: &str = "lsp-rename";
@@ -531,6 +563,12 @@ pub fn server_has_capability(
             }))
         ),
         CAPABILITY_CODE_LENS => server_capabilities.code_lens_provider.is_some(),
+        CAPABILITY_CODE_LENS_RESOLVE => matches!(
+            server_capabilities.code_lens_provider,
+            Some(CodeLensOptions {
+                resolve_provider: Some(true),
+            })
+        ),
         CAPABILITY_CALL_HIERARCHY => match server_capabilities.call_hierarchy_provider {
             Some(CallHierarchyServerCapability::Simple(ok)) => ok,
             Some(_) => true,
@@ -573,6 +611,15 @@ pub fn server_has_capability(
             Some(OneOf::Right(_)) => true,
             None => false,
         },
+        CAPABILITY_INLAY_HINTS_RESOLVE => match &server_capabilities.inlay_hint_provider {
+            Some(OneOf::Right(InlayHintServerCapabilities::Options(options))) => {
+                options.resolve_provider == Some(true)
+            }
+            Some(OneOf::Right(InlayHintServerCapabilities::RegistrationOptions(options))) => {
+                options.inlay_hint_options.resolve_provider == Some(true)
+            }
+            _ => false,
+        },
         CAPABILITY_RANGE_FORMATTING => match server_capabilities.document_range_formatting_provider
         {
             Some(OneOf::Left(ok)) => ok,
@@ -675,12 +722,24 @@ pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
             CAPABILITY_CODE_ACTIONS_RESOLVE,
         );
         probe_feature(to_editor, entry, &mut features, CAPABILITY_CODE_LENS);
+        probe_feature(
+            to_editor,
+            entry,
+            &mut features,
+            CAPABILITY_CODE_LENS_RESOLVE,
+        );
         probe_feature(to_editor, entry, &mut features, CAPABILITY_CALL_HIERARCHY);
         features
             .entry("lsp-diagnostics".to_string())
             .or_default()
             .push(server_name);
         probe_feature(to_editor, entry, &mut features, CAPABILITY_INLAY_HINTS);
+        probe_feature(
+            to_editor,
+            entry,
+            &mut features,
+            CAPABILITY_INLAY_HINTS_RESOLVE,
+        );
 
         // NOTE controller should park request for capabilities until they are available thus it should
         // be safe to unwrap here (otherwise something unexpectedly wrong and it's better to panic)
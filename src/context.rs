@@ -1,9 +1,11 @@
+use crate::diagnostics::DiagnosticsParams;
 use crate::editor_transport::{self, ToEditorSender};
 use crate::language_server_transport::LanguageServerTransport;
+use crate::metrics::Metrics;
 use crate::text_sync::CompiledFileSystemWatcher;
 use crate::thread_worker::Worker;
 use crate::{filetype_to_language_id_map, types::*};
-use jsonrpc_core::{self, Call, Error, Failure, Id, Output, Success, Value, Version};
+use jsonrpc_core::{self, Call, Error, Failure, Id, Output, Params, Success, Value, Version};
 use lsp_types::notification::{Cancel, Notification};
 use lsp_types::request::*;
 use lsp_types::*;
@@ -24,6 +26,31 @@ pub struct Document {
     // Buffer content.
     // It's used to translate between LSP and Kakoune coordinates.
     pub text: ropey::Rope,
+    // Set for read-only documents that are not backed by a file, like class files in jars.
+    // Their contents were provided by this server, which also serves all requests for them.
+    pub content_provider: Option<ServerId>,
+    // Recent earlier versions, oldest first, with the lines changed to get to the next version.
+    // Used to move results that a server computed for an older version to the current text.
+    pub history: VecDeque<(i32, Vec<LineChange>)>,
+    // Language ID and servers the document was opened with.
+    // Used to open it again in a server that was restarted.
+    pub language_id: LanguageId,
+    pub servers: Vec<ServerId>,
+}
+
+/// How many earlier versions of a document to remember.
+pub const DOCUMENT_HISTORY_LENGTH: usize = 16;
+
+/// How many ids of timed-out requests to remember, to recognize late responses to them.
+pub const TIMED_OUT_REQUESTS_LENGTH: usize = 64;
+
+/// Lines of a document that were replaced in a new version, in the order of the document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineChange {
+    /// Where the change starts, as a line of the old version.
+    pub start: usize,
+    pub old_len: usize,
+    pub new_len: usize,
 }
 
 /// Groups parameters for each request.
@@ -36,7 +63,7 @@ pub enum RequestParams<T> {
 
 pub type ResponsesCallback =
     Box<dyn FnOnce(&mut Context, EditorMeta, Vec<(ServerId, Value)>) -> ()>;
-type BatchNumber = usize;
+pub type BatchNumber = usize;
 type BatchCount = BatchNumber;
 
 pub struct OutstandingRequests {
@@ -44,6 +71,19 @@ pub struct OutstandingRequests {
     youngest: Option<Id>,
 }
 
+/// A request that waits for the response of a language server.
+pub struct PendingResponse {
+    pub server_id: ServerId,
+    pub meta: EditorMeta,
+    pub method: &'static str,
+    pub batch_id: BatchNumber,
+    pub canceled: bool,
+    pub sent_at: time::Instant,
+    /// Kept to send the request again if the server asks for that.
+    pub params: Params,
+    pub retries: u32,
+}
+
 pub struct ServerSettings {
     pub name: String,
     pub roots: Vec<RootPath>,
@@ -54,6 +94,14 @@ pub struct ServerSettings {
     pub capabilities: Option<ServerCapabilities>,
     pub settings: Option<Value>,
     pub workaround_eslint: bool,
+    pub diagnostic_filter: DiagnosticFilter,
+    /// The request that started the server, to start it again after a crash.
+    pub start_meta: EditorMeta,
+    pub started_at: time::Instant,
+    /// Crashes in a row, for the restart backoff.
+    pub crash_count: u32,
+    /// Set while a crashed server waits to be restarted.
+    pub restart_at: Option<time::Instant>,
 }
 
 pub struct FileWatcher {
@@ -69,6 +117,15 @@ pub struct Context {
     pub buffer_tombstones: HashSet<String>,
     pub server_tombstones: HashSet<String>,
     pub code_lenses: HashMap<String, Vec<(ServerId, CodeLens)>>,
+    /// Bumped whenever a buffer's code lenses are replaced, to ignore outdated resolve responses.
+    pub code_lens_generations: HashMap<String, u64>,
+    /// The indices of a buffer's code lenses that were sent to codeLens/resolve, so lenses that
+    /// can't be resolved are not asked for again until the lenses are replaced.
+    pub code_lenses_resolve_attempted: HashMap<String, HashSet<usize>>,
+    /// Set while the *diagnostics* buffer is open, to keep it up-to-date.
+    pub diagnostics_buffer: Option<(EditorMeta, DiagnosticsParams)>,
+    /// When to update the *diagnostics* buffer after diagnostics changed.
+    pub diagnostics_buffer_refresh_at: Option<time::Instant>,
     pub completion_items: Vec<(ServerId, CompletionItem)>,
     pub completion_items_timestamp: i32,
     // We currently only track one client's completion items, to simplify cleanup (else we
@@ -87,11 +144,15 @@ pub struct Context {
     pub pending_requests: Vec<EditorRequest>,
     pub pending_requests_from_future: Vec<EditorRequest>,
     pub pending_message_requests: VecDeque<(Id, ServerId, ShowMessageRequestParams)>,
+    /// Message requests whose menu is shown in the editor and that have not been answered yet.
+    pub displayed_message_requests: HashSet<(ServerId, Id)>,
     pub request_counter: u64,
-    pub response_waitlist: HashMap<Id, (EditorMeta, &'static str, BatchNumber, bool)>,
+    pub response_waitlist: HashMap<Id, PendingResponse>,
+    /// The most recent requests that timed out, whose responses may still arrive.
+    pub timed_out_requests: VecDeque<Id>,
     pub session: SessionId,
     pub to_editor: ToEditorSender,
-    pub work_done_progress: HashMap<NumberOrString, Option<WorkDoneProgressBegin>>,
+    pub work_done_progress: HashMap<(ServerId, NumberOrString), Option<WorkDoneProgressBegin>>,
     pub work_done_progress_report_timestamp: time::Instant,
     pub pending_file_watchers:
         HashMap<(ServerId, String, Option<PathBuf>), Vec<CompiledFileSystemWatcher>>,
@@ -99,6 +160,9 @@ pub struct Context {
     #[deprecated]
     pub legacy_filetypes: HashMap<String, (LanguageId, Vec<ServerName>)>,
     pub is_exiting: bool,
+    pub metrics: Metrics,
+    /// Set when replaying a trace, to talk to stub servers instead of starting language servers.
+    pub replaying: bool,
 }
 
 impl Context {
@@ -112,6 +176,10 @@ impl Context {
             buffer_tombstones: Default::default(),
             server_tombstones: Default::default(),
             code_lenses: Default::default(),
+            code_lens_generations: Default::default(),
+            code_lenses_resolve_attempted: Default::default(),
+            diagnostics_buffer: None,
+            diagnostics_buffer_refresh_at: None,
             completion_items: vec![],
             completion_items_timestamp: i32::MAX,
             completion_last_client: None,
@@ -126,8 +194,10 @@ impl Context {
             pending_requests: vec![],
             pending_requests_from_future: vec![],
             pending_message_requests: VecDeque::new(),
+            displayed_message_requests: HashSet::new(),
             request_counter: 0,
             response_waitlist: HashMap::default(),
+            timed_out_requests: VecDeque::new(),
             session,
             to_editor: to_editor.clone(),
             work_done_progress: HashMap::default(),
@@ -136,7 +206,48 @@ impl Context {
             file_watcher: None,
             legacy_filetypes,
             is_exiting: false,
+            metrics: Metrics::default(),
+            replaying: false,
+        }
+    }
+
+    /// The trace level requested for a server.
+    pub fn server_trace(&self, server_name: &ServerName) -> TraceValue {
+        self.config
+            .server
+            .server_trace
+            .get(server_name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// How long to wait for the response to a request, if there is a limit.
+    pub fn request_timeout(&self, method: &str) -> Option<time::Duration> {
+        // Giving up on these would leave the server in limbo.
+        if matches!(
+            method,
+            request::Initialize::METHOD | request::Shutdown::METHOD
+        ) {
+            return None;
         }
+        let timeouts = &self.config.server.request_timeouts;
+        timeouts
+            .get(method)
+            .or_else(|| timeouts.get("*"))
+            .filter(|&&timeout| timeout != 0)
+            .map(|&timeout| time::Duration::from_millis(timeout))
+    }
+
+    /// When the first request that is still waited for times out.
+    pub fn next_request_deadline(&self) -> Option<time::Instant> {
+        self.response_waitlist
+            .values()
+            .filter(|request| !request.canceled)
+            .filter_map(|request| {
+                self.request_timeout(request.method)
+                    .map(|timeout| request.sent_at + timeout)
+            })
+            .min()
     }
 
     pub fn session(&self) -> &SessionId {
@@ -186,7 +297,8 @@ impl Context {
         meta: EditorMeta,
         params: RequestParams<R::Params>,
         callback: F,
-    ) where
+    ) -> BatchNumber
+    where
         R::Params: IntoParams + Clone,
         R::Result: for<'a> Deserialize<'a>,
     {
@@ -218,7 +330,7 @@ impl Context {
                     callback(ctx, meta, results)
                 },
             ),
-        );
+        )
     }
 
     fn batch_call<
@@ -229,7 +341,8 @@ impl Context {
         meta: EditorMeta,
         ops: Vec<(ServerId, R::Params)>,
         callback: F,
-    ) where
+    ) -> BatchNumber
+    where
         R::Params: IntoParams,
         R::Result: for<'a> Deserialize<'a>,
     {
@@ -266,42 +379,59 @@ impl Context {
             let params = params.into_params();
             if params.is_err() {
                 error!(self.to_editor(), "Failed to convert params");
-                return;
+                return batch_id;
             }
-            let id = self.next_request_id();
-            self.response_waitlist
-                .insert(id.clone(), (meta.clone(), R::METHOD, batch_id, false));
+            self.send_request(PendingResponse {
+                server_id,
+                meta: meta.clone(),
+                method: R::METHOD,
+                batch_id,
+                canceled: false,
+                sent_at: time::Instant::now(),
+                params: params.unwrap(),
+                retries: 0,
+            });
+        }
+        batch_id
+    }
 
+    /// Send a request to a server, and wait for its response.
+    /// This also sends requests again that were sent before.
+    pub fn send_request(&mut self, request: PendingResponse) {
+        let id = self.next_request_id();
+        let server_id = request.server_id;
+        let method = request.method;
+        if supersedes_older_requests(method) {
             add_outstanding_request(
                 server_id,
                 self,
-                R::METHOD,
-                meta.buffile.clone(),
-                meta.client.clone(),
+                method,
+                request.meta.buffile.clone(),
+                request.meta.client.clone(),
                 id.clone(),
             );
-
-            let call = jsonrpc_core::MethodCall {
-                jsonrpc: Some(Version::V2),
-                id,
-                method: R::METHOD.into(),
-                params: params.unwrap(),
-            };
-            let server = self.server(server_id);
-            if server
-                .transport
-                .to_lang_server
-                .sender()
-                .send(ServerMessage::Request(Call::MethodCall(call)))
-                .is_err()
-            {
-                error!(self.to_editor(), "Failed to call language server");
-            };
         }
+        let call = jsonrpc_core::MethodCall {
+            jsonrpc: Some(Version::V2),
+            id: id.clone(),
+            method: method.into(),
+            params: request.params.clone(),
+        };
+        self.response_waitlist.insert(id, request);
+        let server = self.server(server_id);
+        if server
+            .transport
+            .to_lang_server
+            .sender()
+            .send(ServerMessage::Request(Call::MethodCall(call)))
+            .is_err()
+        {
+            error!(self.to_editor(), "Failed to call language server");
+        };
     }
 
     pub fn cancel(&mut self, server_id: ServerId, id: Id) {
-        if let Some((_meta, method, _batch_id, _canceled)) = self.response_waitlist.get(&id) {
+        if let Some(PendingResponse { method, .. }) = self.response_waitlist.get(&id) {
             debug!(
                 self.to_editor(),
                 "Canceling request to server {}: {:?} ({})",
@@ -311,7 +441,7 @@ impl Context {
             );
         }
         match self.response_waitlist.get_mut(&id) {
-            Some((_meta, _method, _batch_id, canceled)) => {
+            Some(PendingResponse { canceled, .. }) => {
                 *canceled = true;
             }
             None => {
@@ -427,13 +557,19 @@ impl Context {
         id
     }
 
-    fn next_request_id(&mut self) -> Id {
+    pub fn next_request_id(&mut self) -> Id {
         let id = Id::Num(self.request_counter);
         self.request_counter += 1;
         id
     }
 }
 
+/// Whether a new request makes older ones with the same method for the same buffer obsolete.
+/// Not true for requests that each resolve a different item, which we send in batches.
+fn supersedes_older_requests(method: &str) -> bool {
+    method != CodeLensResolve::METHOD
+}
+
 fn add_outstanding_request(
     server_id: ServerId,
     ctx: &mut Context,
@@ -498,3 +634,34 @@ pub fn remove_outstanding_request(
         key.3.unwrap_or_default()
     );
 }
+
+/// A context with one stub server, whose id is 0.
+#[cfg(test)]
+pub fn context_with_stub_server() -> Context {
+    let mut ctx = Context::new(
+        SessionId("sess".to_string()),
+        editor_transport::mock_to_editor(),
+        Config::default(),
+    );
+    ctx.language_servers.insert(
+        0,
+        ServerSettings {
+            name: "fake".to_string(),
+            roots: vec![],
+            single_instance: None,
+            offset_encoding: OffsetEncoding::default(),
+            preferred_offset_encoding: None,
+            // The stub server's messages are disconnected right away, like a crashed server's.
+            transport: crate::language_server_transport::stub(ctx.to_editor()),
+            capabilities: None,
+            settings: None,
+            workaround_eslint: false,
+            diagnostic_filter: DiagnosticFilter::default(),
+            start_meta: EditorMeta::default(),
+            started_at: time::Instant::now(),
+            crash_count: 0,
+            restart_at: None,
+        },
+    );
+    ctx
+}
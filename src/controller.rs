@@ -1,7 +1,9 @@
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fs::{self};
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::mem;
 use std::ops::ControlFlow;
 use std::os::fd::AsRawFd;
@@ -11,39 +13,43 @@ use std::str::FromStr;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::capabilities::{self, initialize};
-use crate::context::Context;
+use crate::context::{Context, TIMED_OUT_REQUESTS_LENGTH};
 use crate::editor_transport::{self, ToEditorSender};
 use crate::language_features::lean::{
     PlainGoalRequest, PlainGoalResponse, PlainTermGoalRequest, PlainTermGoalResponse,
 };
 use crate::language_features::{selection_range, *};
+use crate::language_server_transport::{self, LanguageServerTransport};
 use crate::log::DEBUG;
 use crate::progress;
 use crate::project_root::find_project_root;
 use crate::show_message::{self, MessageRequestResponse};
+use crate::status;
 use crate::text_sync::*;
 use crate::thread_worker::Worker;
+use crate::trace::{self, TraceEntry};
 use crate::types::*;
 use crate::util::*;
 use crate::workspace::{
     self, EditorApplyEdit, EditorDidChangeConfigurationParams, EditorExecuteCommand,
 };
+use crate::LAST_CLIENT;
 use crate::{context::*, set_logger};
 use crate::{diagnostics, do_cleanup};
-use crate::{language_server_transport, LAST_CLIENT};
 use ccls::{EditorCallParams, EditorInheritanceParams, EditorMemberParams, EditorNavigateParams};
-use code_lens::{text_document_code_lens, CodeLensOptions};
-use crossbeam_channel::{after, never, tick, Receiver, Select, Sender};
+use code_lens::{text_document_code_lens, CodeLensOptions, CodeLensRequestParams, VisibleLines};
+use crossbeam_channel::{after, at, never, tick, Receiver, Select, Sender};
+use diagnostics::{DiagnosticsExportParams, DiagnosticsParams};
 use indoc::formatdoc;
 use inlay_hints::{InlayHintApplyParams, InlayHintsOptions};
 use itertools::Itertools;
-use jsonrpc_core::{Call, ErrorCode, MethodCall, Output, Params};
+use jsonrpc_core::{Call, ErrorCode, Id, MethodCall, Output, Params};
 use lean::EditorPlainGoalParams;
 use libc::O_NONBLOCK;
-use lsp_types::error_codes::CONTENT_MODIFIED;
+use lsp_types::error_codes::{CONTENT_MODIFIED, SERVER_CANCELLED};
 use lsp_types::notification::Notification;
 use lsp_types::request::Request;
 use lsp_types::*;
@@ -68,6 +74,8 @@ pub struct Tokenizer {
     pub input: SharedBuffer,
     pub input_offset: usize,
     pub output: Vec<u8>,
+    /// A token that was read ahead, to be returned by the next read.
+    pub unread: Option<String>,
 }
 
 impl Tokenizer {
@@ -77,42 +85,101 @@ impl Tokenizer {
             ..Default::default()
         }
     }
+    /// Read the next token. Returns None at exit, and an error for input that is not
+    /// quoted properly.
     pub fn read_token<'a, 'b: 'a>(
         &'b mut self,
         mut at_end: impl FnMut(MutexGuard<'a, Vec<u8>>, &Condvar) -> Option<MutexGuard<'a, Vec<u8>>>,
-    ) -> Option<String> {
+    ) -> Option<Result<String, String>> {
+        if let Some(token) = self.unread.take() {
+            return Some(Ok(token));
+        }
         let mut input = self.input.lock();
         let mut escaped = false;
         let mut quoted = false;
         let mut offset = self.input_offset;
+        let mut token_start = Some(offset);
         loop {
             if offset == input.len() {
+                token_start = token_start.filter(|&start| start == offset).map(|_| 0);
                 input.clear();
                 input = (at_end)(input, &self.input.notifier)?;
                 offset = 0;
             }
             let c = input[offset];
             offset += 1;
-            if tokenizer_take_byte(&mut self.output, offset - 1, c, &mut escaped, &mut quoted)
-                .is_break()
-            {
-                break;
+            match tokenizer_take_byte(&mut self.output, offset - 1, c, &mut escaped, &mut quoted) {
+                Ok(ControlFlow::Continue(())) => (),
+                Ok(ControlFlow::Break(())) => break,
+                Err(err) => {
+                    // Continue right after the start of the broken token, in case a stray quote
+                    // made it swallow the start of the next request.
+                    self.input_offset = token_start.map_or(offset, |start| start + 1);
+                    self.output.clear();
+                    return Some(Err(err));
+                }
             }
         }
         self.input_offset = offset;
         let token = String::from_utf8_lossy(&self.output).to_string();
         self.output.clear();
-        Some(token)
+        Some(Ok(token))
+    }
+
+    /// Skip the rest of a broken request, up to the next token that equals the given one,
+    /// which will be read again.
+    ///
+    /// This looks for the quoted token between spaces in the raw input, so it works even if
+    /// a stray quote made the tokenizer lose track of what is quoted. Inside quoted tokens,
+    /// quotes are always escaped.
+    pub fn skip_to<'a, 'b: 'a>(
+        &'b mut self,
+        boundary: &str,
+        mut at_end: impl FnMut(MutexGuard<'a, Vec<u8>>, &Condvar) -> Option<MutexGuard<'a, Vec<u8>>>,
+    ) -> Option<()> {
+        self.unread = None;
+        self.output.clear();
+        let pattern = quote_token(boundary) + " ";
+        let pattern = pattern.as_bytes();
+        let mut input = self.input.lock();
+        let mut offset = self.input_offset;
+        let mut previous = offset.checked_sub(1).map_or(b' ', |offset| input[offset]);
+        let mut matched = 0;
+        while matched < pattern.len() {
+            if offset == input.len() {
+                input.clear();
+                input = (at_end)(input, &self.input.notifier)?;
+                offset = 0;
+            }
+            let c = input[offset];
+            offset += 1;
+            matched = if c == pattern[matched] && (matched != 0 || previous == b' ') {
+                matched + 1
+            } else if c == pattern[0] && previous == b' ' {
+                1
+            } else {
+                0
+            };
+            previous = c;
+        }
+        self.input_offset = offset;
+        self.unread = Some(boundary.to_string());
+        Some(())
     }
 }
 
+/// Quote a token so that the tokenizer reads it back as is.
+fn quote_token(token: &str) -> String {
+    format!("'{}'", token.replace('\'', "'\\''"))
+}
+
 fn tokenizer_take_byte(
     output: &mut Vec<u8>,
     offset: usize,
     c: u8,
     escaped: &mut bool,
     quoted: &mut bool,
-) -> ControlFlow<()> {
+) -> Result<ControlFlow<()>, String> {
     if *escaped {
         output.push(c);
         *escaped = false;
@@ -124,43 +191,64 @@ fn tokenizer_take_byte(
         }
     } else {
         match c {
-            b' ' => return ControlFlow::Break(()),
+            b' ' => return Ok(ControlFlow::Break(())),
             b'\'' => *quoted = true,
             b'\\' => *escaped = true,
             _ => {
-                panic!(
+                return Err(format!(
                     "expected quote, backslash or space at offset {offset}, saw '{}'",
                     char::from(c)
-                )
+                ))
             }
         }
     }
-    ControlFlow::Continue(())
+    Ok(ControlFlow::Continue(()))
 }
 
 struct ParserState {
     force_exit: Arc<AtomicBool>,
     to_editor: ToEditorSender,
+    /// Every request starts with this, so it marks where to continue after a broken request.
+    session: String,
+    /// The client and hook flag of the current request, to report errors.
+    client: ClientId,
+    hook: bool,
+    /// Lines of buffer text that the current request has yet to consume from the text buffer.
+    text_line_count: usize,
+    /// Why the current request could not be parsed.
+    error: Option<String>,
     tokenizer: Tokenizer,
     text_buffer: SharedBuffer,
     debug: bool,
     debug_output: String,
+    trace: bool,
+    trace_tokens: Vec<String>,
+    trace_texts: Vec<String>,
 }
 
 impl ParserState {
     fn new(
         force_exit: Arc<AtomicBool>,
         to_editor: ToEditorSender,
+        session: String,
         command_buffer: SharedBuffer,
         text_buffer: SharedBuffer,
     ) -> Self {
         ParserState {
             force_exit,
             to_editor,
+            session,
+            client: ClientId::default(),
+            hook: false,
+            text_line_count: 0,
+            error: None,
             tokenizer: Tokenizer::new(command_buffer),
             text_buffer,
             debug: false,
             debug_output: String::new(),
+            trace: false,
+            trace_tokens: vec![],
+            trace_texts: vec![],
         }
     }
 }
@@ -181,12 +269,19 @@ fn at_end<'a>(
 
 fn next_string(state: &mut ParserState) -> Option<String> {
     let (tokenizer, force_exit) = (&mut state.tokenizer, &state.force_exit);
-    let token = tokenizer.read_token(|buffer, notifier| at_end(force_exit, buffer, notifier))?;
+    let token =
+        match tokenizer.read_token(|buffer, notifier| at_end(force_exit, buffer, notifier))? {
+            Ok(token) => token,
+            Err(err) => return state.fail(err),
+        };
     if state.debug {
         state.debug_output.push_str(" {");
         state.debug_output.push_str(&token);
         state.debug_output.push('}');
     }
+    if state.trace {
+        state.trace_tokens.push(token.clone());
+    }
     Some(token)
 }
 
@@ -249,7 +344,11 @@ where
     <T as FromString>::Err: std::fmt::Debug,
 {
     fn deserialize(state: &mut ParserState) -> Option<Self> {
-        Some(T::from_string(next_string(state)?).unwrap())
+        let token = next_string(state)?;
+        match T::from_string(token.clone()) {
+            Ok(value) => Some(value),
+            Err(err) => state.fail(format!("invalid token '{}': {:?}", token, err)),
+        }
     }
 }
 impl Deserializable for KakounePosition {
@@ -260,6 +359,23 @@ impl Deserializable for KakounePosition {
         })
     }
 }
+impl Deserializable for CodeLensRequestParams {
+    fn deserialize(state: &mut ParserState) -> Option<Self> {
+        let virtual_lines = state.next()?;
+        // %val{window_range}: 0-based top line, left column, height, width.
+        let top: u32 = state.next()?;
+        let _left: u32 = state.next()?;
+        let height: u32 = state.next()?;
+        let _width: u32 = state.next()?;
+        Some(CodeLensRequestParams {
+            virtual_lines,
+            visible_lines: (height != 0).then_some(VisibleLines {
+                first: top + 1,
+                last: top + height,
+            }),
+        })
+    }
+}
 impl Deserializable for FormattingOptions {
     fn deserialize(state: &mut ParserState) -> Option<Self> {
         Some(FormattingOptions {
@@ -274,6 +390,33 @@ impl Deserializable for FormattingOptions {
 }
 
 impl ParserState {
+    /// Give up on the current request.
+    pub fn fail<T>(&mut self, error: impl Into<String>) -> Option<T> {
+        self.error = Some(error.into());
+        None
+    }
+
+    /// Report a broken request and skip to the next one.
+    fn recover(&mut self) -> Option<()> {
+        let error = self.error.take()?;
+        handle_broken_editor_request(
+            &self.to_editor,
+            &self.client,
+            self.hook,
+            "editor request",
+            error,
+        );
+        // Drop the text of the broken request so the next one doesn't read it.
+        let text_line_count = mem::take(&mut self.text_line_count);
+        if text_line_count != 0 {
+            self.take_lines(text_line_count)?;
+        }
+        let (tokenizer, force_exit) = (&mut self.tokenizer, &self.force_exit);
+        tokenizer.skip_to(&self.session, |buffer, notifier| {
+            at_end(force_exit, buffer, notifier)
+        })
+    }
+
     pub fn next<T: Deserializable>(&mut self) -> Option<T> {
         T::deserialize(self)
     }
@@ -299,17 +442,36 @@ impl ParserState {
     }
 
     pub fn text_of_buffer(&mut self) -> Option<String> {
-        let buf_line_count: usize = self.next()?;
+        let buf_line_count = mem::take(&mut self.text_line_count);
+        if buf_line_count == 0 {
+            return self.fail("missing buffer text".to_string());
+        }
+        let Some(text) = self.take_lines(buf_line_count) else {
+            return self.fail(format!("invalid line count {}", buf_line_count));
+        };
+        let result = String::from_utf8_lossy(&text).to_string();
+        debug!(
+            &self.to_editor,
+            "Buffer contents from editor: {{{}}}", &result
+        );
+        if self.trace {
+            self.trace_texts.push(result.clone());
+        }
+        Some(result)
+    }
+
+    /// Removes the given number of lines from the text buffer, waiting for them to arrive.
+    fn take_lines(&mut self, line_count: usize) -> Option<Vec<u8>> {
         let mut text_buffer = self.text_buffer.lock();
         let count_lines = |s: &[u8]| s.iter().filter(|&&c| c == b'\n').count();
         let mut available_lines = count_lines(&text_buffer);
         let mut offset = text_buffer.len();
-        while available_lines < buf_line_count {
+        while available_lines < line_count {
             text_buffer = at_end(&self.force_exit, text_buffer, &self.text_buffer.notifier)?;
             available_lines += count_lines(&text_buffer[offset..]);
             offset = text_buffer.len();
         }
-        let excess_newlines = available_lines - buf_line_count;
+        let excess_newlines = available_lines - line_count;
         let last_newline_offset = text_buffer
             .iter()
             .enumerate()
@@ -317,29 +479,30 @@ impl ParserState {
             .filter(|(_i, &c)| c == b'\n')
             .skip(excess_newlines)
             .map(|(i, _c)| i)
-            .next()
-            .unwrap();
+            .next();
+        let last_newline_offset = last_newline_offset?;
         let mut tmp = text_buffer.split_off(last_newline_offset + 1);
         mem::swap(&mut tmp, &mut text_buffer);
-        let result = String::from_utf8_lossy(&tmp).to_string();
-        debug!(
-            &self.to_editor,
-            "Buffer contents from editor: {{{}}}", &result
-        );
-        Some(result)
+        Some(tmp)
     }
 }
 
 const COMPLETE_EXECUTE_COMMAND: &str = "kakoune/complete/workspace/executeCommand";
 
-// Returns none on normal or forced exit.
+// Returns none on normal or forced exit, and on broken requests, which set state.error.
 fn dispatch_fifo_request(
     state: &mut ParserState,
     from_editor: &Sender<EditorRequest>,
 ) -> Option<()> {
+    state.client = ClientId::default();
+    state.hook = false;
+    state.text_line_count = 0;
     let session = SessionId(state.next()?);
     let client = ClientId(state.next()?);
+    state.client = client.clone();
+    state.text_line_count = state.next()?;
     let hook = state.next()?;
+    state.hook = hook;
     let sourcing = state.next()?;
     let mut buffile = state.next()?;
     let mut version = state.next()?;
@@ -399,7 +562,10 @@ fn dispatch_fifo_request(
             sync_trailer(state, is_sync)?;
             params
         }
-        "capabilities" => Box::new(()),
+        "capabilities"
+        | "kakoune/diagnostics-buffer-closed"
+        | "kakoune/status"
+        | "kakoune/metrics" => Box::new(()),
         "codeAction/resolve" => Box::new(CodeActionResolveParams {
             code_action: state.next()?,
         }),
@@ -425,6 +591,16 @@ fn dispatch_fifo_request(
         "kakoune/goto-document-symbol" => Box::new(GotoSymbolParams {
             goto_symbol: state.next()?,
         }),
+        "kakoune/diagnostics-export" => Box::new(DiagnosticsExportParams {
+            format: state.next()?,
+            path: state.next()?,
+        }),
+        "kakoune/diagnostic-open-docs"
+        | "kakoune/diagnostic-related"
+        | "kakoune/inlay-hint-goto"
+        | "kakoune/inlay-hint-hover" => Box::new(PositionParams {
+            position: state.next()?,
+        }),
         "kakoune/inlay-hint-apply-nearest" => Box::new(InlayHintApplyParams {
             selections_desc: {
                 let selection_count = state.next()?;
@@ -446,12 +622,12 @@ fn dispatch_fifo_request(
                 search_next: match state.next::<String>()?.as_str() {
                     "next" => true,
                     "previous" => false,
-                    _ => panic!("invalid request"),
+                    direction => return state.fail(format!("invalid direction {}", direction)),
                 },
                 hover: match state.next::<String>()?.as_str() {
                     "hover" => true,
                     "goto" => false,
-                    _ => panic!("invalid request"),
+                    action => return state.fail(format!("invalid action {}", action)),
                 },
                 symbol_kinds: state.next_vec(num_symbol_kinds)?,
             })
@@ -470,26 +646,46 @@ fn dispatch_fifo_request(
         }),
         "kakoune/textDocument/codeLens" => Box::new(CodeLensOptions {
             selection_desc: state.next()?,
+            index: state.next::<String>()?.parse().ok(),
         }),
         "kakoune/did-change-option" => {
             let hook_param = state.next::<String>()?;
             let Some((key, value)) = hook_param.split_once('=') else {
-                panic!("invalid request");
+                return state.fail(format!("invalid option change {}", hook_param));
             };
-            match key {
-                "lsp_debug" => {
-                    let debug = bool::from_str(value).unwrap();
-                    DEBUG.store(debug, Relaxed);
-                    set_logger(if debug {
-                        Severity::Debug
-                    } else {
-                        Severity::Info
-                    });
+            // Servers need to be told about trace levels, so they are handled like other requests.
+            if key == "lsp_server_trace" {
+                match parse_server_trace(value) {
+                    Ok(server_trace) => Box::new(server_trace),
+                    Err(err) => {
+                        handle_broken_editor_request(
+                            &state.to_editor,
+                            &client,
+                            hook,
+                            "%opt{lsp_server_trace}",
+                            err,
+                        );
+                        return Some(());
+                    }
+                }
+            } else {
+                match key {
+                    "lsp_debug" => {
+                        let Ok(debug) = bool::from_str(value) else {
+                            return state.fail(format!("invalid value of lsp_debug: {}", value));
+                        };
+                        DEBUG.store(debug, Relaxed);
+                        set_logger(if debug {
+                            Severity::Debug
+                        } else {
+                            Severity::Info
+                        });
+                    }
+                    _ => return state.fail(format!("unknown option {}", key)),
                 }
-                _ => panic!("unknown key: {}", key),
+                debug!(&state.to_editor, "Applied option change {}", hook_param);
+                return Some(());
             }
-            debug!(&state.to_editor, "Applied option change {}", hook_param);
-            return Some(());
         }
         "rust-analyzer/expandMacro" => Box::new(PositionParams {
             position: state.next()?,
@@ -513,13 +709,15 @@ fn dispatch_fifo_request(
                         }
                     }
                     "matching" => Some(CodeActionFilter::ByRegex(state.next()?)),
-                    _ => panic!("invalid request"),
+                    filter => return state.fail(format!("invalid filter {}", filter)),
                 },
             });
             sync_trailer(state, is_sync)?;
             params
         }
-        "textDocument/codeLens" => Box::new(()),
+        "textDocument/codeLens" | "kakoune/code-lens-resolve" => {
+            Box::new(state.next::<CodeLensRequestParams>()?)
+        }
         "textDocument/completion" => Box::new(TextDocumentCompletionParams {
             position: state.next()?,
             completion: EditorCompletion {
@@ -537,8 +735,9 @@ fn dispatch_fifo_request(
         | "textDocument/typeDefinition" => Box::new(PositionParams {
             position: state.next()?,
         }),
-        "textDocument/diagnostics" => Box::new(PositionParams {
+        "textDocument/diagnostics" => Box::new(DiagnosticsParams {
             position: state.next()?,
+            errors_only: state.next::<String>()? == "-errors",
         }),
         "textDocument/documentSymbol" => Box::new(PositionParams {
             position: state.next()?,
@@ -576,6 +775,7 @@ fn dispatch_fifo_request(
         }),
         "textDocument/inlayHint" => Box::new(InlayHintsOptions {
             buf_line_count: state.next()?,
+            max_length: state.next()?,
         }),
         "textDocument/prepareCallHierarchy" => Box::new(CallHierarchyParams {
             position: state.next()?,
@@ -619,12 +819,21 @@ fn dispatch_fifo_request(
         "textDocument/semanticTokens/full" => Box::new(()),
         "textDocument/switchSourceHeader" => Box::new(()),
         "window/showMessageRequest/showNext" => Box::new(()),
-        "window/showMessageRequest/respond" => Box::new(MessageRequestResponse {
-            message_request_id: serde_json::from_str(&state.next::<String>()?).unwrap(),
-            item: state
-                .next::<Option<String>>()?
-                .map(|s| toml::from_str(&s).unwrap()),
-        }),
+        "window/showMessageRequest/respond" => {
+            let message_request_id = state.next::<String>()?;
+            let item = state.next::<Option<String>>()?;
+            let Ok(message_request_id) = serde_json::from_str(&message_request_id) else {
+                return state.fail(format!("invalid request ID {}", message_request_id));
+            };
+            let item = match item.map(|item| toml::from_str(&item)).transpose() {
+                Ok(item) => item,
+                Err(err) => return state.fail(format!("invalid message action: {}", err)),
+            };
+            Box::new(MessageRequestResponse {
+                message_request_id,
+                item,
+            })
+        }
         "window/workDoneProgress/cancel" => Box::new(WorkDoneProgressCancelParams {
             token: state.next()?,
         }),
@@ -665,9 +874,19 @@ fn dispatch_fifo_request(
             params
         }
         method => {
-            panic!("unexpected method {}", method);
+            return state.fail(format!("unexpected method {}", method));
         }
     });
+    if state.trace {
+        let tokens = mem::take(&mut state.trace_tokens);
+        let texts = mem::take(&mut state.trace_texts);
+        if let Err(err) = trace::editor_request(tokens, texts) {
+            error!(
+                &state.to_editor,
+                "Failed to write trace, stopping it: {}", err
+            );
+        }
+    }
 
     let parse_error = |what, err| {
         handle_broken_editor_request(&state.to_editor, &client, hook, what, err);
@@ -855,6 +1074,7 @@ pub fn start(
         )
     };
     let fifo_worker = {
+        let session = ctx.session().to_string();
         let force_exit = force_exit.clone();
         let command_buffer = command_buffer.clone();
         let text_buffer = text_buffer.clone();
@@ -864,10 +1084,13 @@ pub fn start(
             1024, // arbitrary
             move |to_editor, _receiver: Receiver<()>, from_editor: Sender<EditorRequest>| {
                 let mut state =
-                    ParserState::new(force_exit, to_editor, command_buffer, text_buffer);
+                    ParserState::new(force_exit, to_editor, session, command_buffer, text_buffer);
                 loop {
                     state.debug = DEBUG.load(Relaxed);
-                    let done = dispatch_fifo_request(&mut state, &from_editor).is_none();
+                    state.trace = trace::enabled();
+                    state.trace_tokens.clear();
+                    state.trace_texts.clear();
+                    let mut done = dispatch_fifo_request(&mut state, &from_editor).is_none();
                     if state.debug
                         && !(state.force_exit.load(Relaxed) && state.debug_output.is_empty())
                     {
@@ -878,6 +1101,9 @@ pub fn start(
                         );
                         state.debug_output.clear();
                     }
+                    if done && state.error.is_some() {
+                        done = state.recover().is_none();
+                    }
                     if done {
                         break;
                     }
@@ -891,11 +1117,7 @@ pub fn start(
     };
 
     'event_loop: loop {
-        let server_rxs: Vec<&Receiver<ServerMessage>> = ctx
-            .language_servers
-            .values()
-            .map(|settings| settings.transport.from_lang_server.receiver())
-            .collect();
+        let (server_ids, server_rxs) = running_servers(ctx);
         let from_editor = fifo_worker.receiver();
         let never_rx = never();
         let from_file_watcher = ctx
@@ -921,7 +1143,7 @@ pub fn start(
 
         let mut sel = Select::new();
         // Server receivers are registered first so we can match their order
-        // with servers in the context.
+        // with server IDs.
         for rx in &server_rxs {
             sel.recv(rx);
         }
@@ -936,6 +1158,24 @@ pub fn start(
         };
         let timeout_op = sel.recv(&timeout_channel);
 
+        let restart_channel = ctx
+            .language_servers
+            .values()
+            .filter_map(|settings| settings.restart_at)
+            .min()
+            .map(at)
+            .unwrap_or_else(never);
+        let restart_op = sel.recv(&restart_channel);
+
+        let request_deadline_channel = ctx.next_request_deadline().map(at).unwrap_or_else(never);
+        let request_deadline_op = sel.recv(&request_deadline_channel);
+
+        let diagnostics_refresh_channel = ctx
+            .diagnostics_buffer_refresh_at
+            .map(at)
+            .unwrap_or_else(never);
+        let diagnostics_refresh_op = sel.recv(&diagnostics_refresh_channel);
+
         let trigger_force_exit = || {
             {
                 let _command_buffer = command_buffer.lock();
@@ -957,6 +1197,20 @@ pub fn start(
                 trigger_force_exit();
                 break 'event_loop;
             }
+            idx if idx == restart_op => {
+                op.recv(&restart_channel).unwrap();
+                restart_servers(ctx);
+            }
+            idx if idx == request_deadline_op => {
+                op.recv(&request_deadline_channel).unwrap();
+                if time_out_requests(ctx).is_break() {
+                    break 'event_loop;
+                }
+            }
+            idx if idx == diagnostics_refresh_op => {
+                op.recv(&diagnostics_refresh_channel).unwrap();
+                diagnostics::refresh_diagnostics_buffer(ctx);
+            }
             idx if idx == from_editor_op => {
                 debug!(ctx.to_editor(), "Received editor request via fifo");
                 let editor_request = match op.recv(from_editor) {
@@ -1012,178 +1266,17 @@ pub fn start(
             }
             i => {
                 let msg = op.recv(server_rxs[i]);
-                let server_id = ctx.language_servers.iter().nth(i).map(|(s, _)| *s).unwrap();
+                let server_id = server_ids[i];
 
-                if let Err(err) = msg {
-                    warn!(ctx.to_editor(), "received error from server: {err}");
-                    trigger_force_exit();
-                    break 'event_loop;
-                }
-                let msg = msg.unwrap();
-                match msg {
-                    ServerMessage::Request(call) => match call {
-                        Call::MethodCall(request) => {
-                            dispatch_server_request(server_id, EditorMeta::default(), request, ctx);
-                        }
-                        Call::Notification(notification) => {
-                            dispatch_server_notification(
-                                server_id,
-                                EditorMeta::default(),
-                                &notification.method,
-                                notification.params,
-                                ctx,
-                            );
-                        }
-                        Call::Invalid { id } => {
-                            error!(
-                                ctx.to_editor(),
-                                "Invalid call from language server: {:?}", id
-                            );
-                        }
-                    },
-                    ServerMessage::Response(output) => {
-                        match output {
-                            Output::Success(success) => {
-                                if let Some((meta, method, batch_id, canceled)) =
-                                    ctx.response_waitlist.remove(&success.id)
-                                {
-                                    if canceled {
-                                        continue;
-                                    }
-                                    remove_outstanding_request(
-                                        server_id,
-                                        ctx,
-                                        method,
-                                        meta.buffile.clone(),
-                                        meta.client.clone(),
-                                        &success.id,
-                                    );
-                                    if let Some((mut vals, callback)) =
-                                        ctx.batches.remove(&batch_id)
-                                    {
-                                        if let Some(batch_seq) = ctx.batch_sizes.remove(&batch_id) {
-                                            vals.push((server_id, success.result));
-                                            let batch_size: usize = batch_seq.values().sum();
-
-                                            if vals.len() >= batch_size {
-                                                callback(ctx, meta, vals);
-                                                if ctx.is_exiting {
-                                                    break 'event_loop;
-                                                }
-                                            } else {
-                                                ctx.batch_sizes.insert(batch_id, batch_seq);
-                                                ctx.batches.insert(batch_id, (vals, callback));
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    error!(
-                                        ctx.to_editor(),
-                                        "Id {:?} is not in waitlist!", success.id
-                                    );
-                                }
-                            }
-                            Output::Failure(failure) => {
-                                if let Some(request) = ctx.response_waitlist.remove(&failure.id) {
-                                    let (meta, method, batch_id, canceled) = request;
-                                    if canceled {
-                                        continue;
-                                    }
-                                    remove_outstanding_request(
-                                        server_id,
-                                        ctx,
-                                        method,
-                                        meta.buffile.clone(),
-                                        meta.client.clone(),
-                                        &failure.id,
-                                    );
-                                    if failure.error.code
-                                        == ErrorCode::ServerError(CONTENT_MODIFIED)
-                                    {
-                                        debug!(
-                                            ctx.to_editor(),
-                                            "Error response from server {}: {:?}",
-                                            &ctx.server(server_id).name,
-                                            failure
-                                        );
-                                    } else {
-                                        error!(
-                                            ctx.to_editor(),
-                                            "Error response from server {}: {:?}",
-                                            &ctx.server(server_id).name,
-                                            failure
-                                        );
-                                    }
-                                    if let Some((vals, callback)) = ctx.batches.remove(&batch_id) {
-                                        if let Some(mut batch_seq) =
-                                            ctx.batch_sizes.remove(&batch_id)
-                                        {
-                                            batch_seq.remove(&server_id);
-
-                                            // We con only keep going if there are still other servers to respond.
-                                            // Otherwise, skip the following block and handle failure.
-                                            if !batch_seq.is_empty() {
-                                                // Remove this failing language server from the batch, allowing
-                                                // working ones to still be handled.
-                                                let vals: Vec<_> = vals
-                                                    .into_iter()
-                                                    .filter(|(s, _)| *s != server_id)
-                                                    .collect();
-
-                                                // Scenario: this failing server is holding back the response handling
-                                                // for all other servers, which already responded successfully.
-                                                let batch_size: usize = batch_seq.values().sum();
-                                                if vals.len() >= batch_size {
-                                                    callback(ctx, meta, vals);
-                                                    if ctx.is_exiting {
-                                                        break 'event_loop;
-                                                    }
-                                                } else {
-                                                    // Re-insert the batch, as we have no business with it at the moment,
-                                                    // since not all servers have completely responded.
-                                                    ctx.batch_sizes.insert(batch_id, batch_seq);
-                                                    ctx.batches.insert(batch_id, (vals, callback));
-                                                }
-
-                                                continue;
-                                            }
-                                        }
-                                    }
-                                    match failure.error.code {
-                                        code if code
-                                            == ErrorCode::ServerError(CONTENT_MODIFIED)
-                                            || method == request::CodeActionRequest::METHOD => {}
-                                        code => {
-                                            let msg = match code {
-                                                ErrorCode::MethodNotFound => format!(
-                                                    "language server {} doesn't support method {}",
-                                                    &ctx.server(server_id).name,
-                                                    method
-                                                ),
-                                                _ => format!(
-                                                    "language server {} error: {}",
-                                                    &ctx.server(server_id).name,
-                                                    editor_quote(&failure.error.message)
-                                                ),
-                                            };
-                                            ctx.show_error(meta, msg);
-                                        }
-                                    }
-                                } else {
-                                    error!(
-                                        ctx.to_editor(),
-                                        "Error response from server {}: {:?}",
-                                        &ctx.server(server_id).name,
-                                        failure
-                                    );
-                                    error!(
-                                        ctx.to_editor(),
-                                        "Id {:?} is not in waitlist!", failure.id
-                                    );
-                                }
-                            }
-                        }
+                if msg.is_err() {
+                    handle_server_crash(ctx, server_id);
+                    if ctx.is_exiting {
+                        break 'event_loop;
                     }
+                    continue;
+                }
+                if process_server_message(ctx, server_id, msg.unwrap()).is_break() {
+                    break 'event_loop;
                 }
             }
         }
@@ -1208,6 +1301,391 @@ pub fn start(
     stop_session(ctx);
 }
 
+/// Replay a trace written with the `lsp_trace_file` option, against stub language servers.
+///
+/// Editor requests are parsed again, and the recorded messages from language servers are handled
+/// in trace order. Responses are matched to the replayed requests by server and method.
+/// Everything kak-lsp sends to the editor and to the language servers is written to `out`.
+pub fn replay(config: Config, entries: Vec<TraceEntry>, out: &mut dyn Write) {
+    let (to_editor, editor_responses) = crossbeam_channel::unbounded();
+    // Every editor request starts with the session name.
+    let session = entries
+        .iter()
+        .find(|entry| entry.kind == trace::EDITOR_REQUEST)
+        .and_then(|entry| entry.tokens.first().cloned())
+        .unwrap_or_default();
+    let mut ctx = Context::new(SessionId(session), to_editor.clone(), config);
+    ctx.replaying = true;
+    let ctx = &mut ctx;
+
+    let command_buffer = SharedBuffer::default();
+    let text_buffer = SharedBuffer::default();
+    // Don't wait for input that is not in the trace.
+    let force_exit = Arc::new(AtomicBool::new(true));
+    let mut state = ParserState::new(
+        force_exit,
+        to_editor,
+        ctx.session().to_string(),
+        command_buffer.clone(),
+        text_buffer.clone(),
+    );
+    let (from_editor, editor_requests) = crossbeam_channel::unbounded();
+
+    let out = RefCell::new(out);
+    let print = |line: String| {
+        let _ = writeln!(out.borrow_mut(), "{}", line);
+    };
+    // The recorded requests to each server, to be matched with the replayed ones.
+    let mut recorded_requests: Vec<(ServerName, String, Id)> = entries
+        .iter()
+        .filter(|entry| entry.kind == "send-request")
+        .filter_map(|entry| {
+            let call: MethodCall = serde_json::from_value(entry.message.clone()?).ok()?;
+            Some((entry.server.clone()?, call.method, call.id))
+        })
+        .collect();
+    let mut replayed_ids: HashMap<(ServerName, Id), Id> = HashMap::new();
+    // Responses to requests that have not been replayed yet.
+    let mut deferred: Vec<(ServerName, ServerMessage)> = vec![];
+
+    let mut drain = |ctx: &mut Context, replayed_ids: &mut HashMap<(ServerName, Id), Id>| {
+        for response in editor_responses.try_iter() {
+            match &response.meta.client {
+                Some(client) => print(format!("To editor client {}: {}", client, response.command)),
+                None => print(format!("To editor: {}", response.command)),
+            }
+        }
+        for server in ctx.language_servers.values() {
+            let Some(sent) = &server.transport.stub_receiver else {
+                continue;
+            };
+            for message in sent.try_iter() {
+                let text = match &message {
+                    ServerMessage::Request(request) => serde_json::to_string(request),
+                    ServerMessage::Response(response) => serde_json::to_string(response),
+                }
+                .unwrap();
+                print(format!("To server {}: {}", server.name, text));
+                let ServerMessage::Request(Call::MethodCall(call)) = message else {
+                    continue;
+                };
+                if let Some(i) = recorded_requests
+                    .iter()
+                    .position(|(name, method, _)| name == &server.name && method == &call.method)
+                {
+                    let (name, _, recorded_id) = recorded_requests.remove(i);
+                    replayed_ids.insert((name, recorded_id), call.id);
+                }
+            }
+        }
+    };
+
+    // Returns the message if it can't be handled yet.
+    let inject = |ctx: &mut Context,
+                  replayed_ids: &HashMap<(ServerName, Id), Id>,
+                  server_name: ServerName,
+                  mut message: ServerMessage|
+     -> Option<(ServerName, ServerMessage)> {
+        let Some(server_id) = ctx
+            .language_servers
+            .iter()
+            .find(|(_, server)| server.name == server_name)
+            .map(|(&server_id, _)| server_id)
+        else {
+            return Some((server_name, message));
+        };
+        if let ServerMessage::Response(output) = &mut message {
+            let id = match output {
+                Output::Success(success) => &mut success.id,
+                Output::Failure(failure) => &mut failure.id,
+            };
+            match replayed_ids.get(&(server_name.clone(), id.clone())) {
+                Some(replayed_id) => *id = replayed_id.clone(),
+                None => return Some((server_name, message)),
+            }
+        }
+        print(format!(
+            "From server {}: {}",
+            server_name,
+            match &message {
+                ServerMessage::Request(request) => serde_json::to_string(request),
+                ServerMessage::Response(response) => serde_json::to_string(response),
+            }
+            .unwrap()
+        ));
+        let _ = process_server_message(ctx, server_id, message);
+        None
+    };
+
+    for entry in entries {
+        if entry.kind == trace::EDITOR_REQUEST {
+            {
+                let mut commands = command_buffer.lock();
+                for token in &entry.tokens {
+                    commands.extend_from_slice(quote_token(token).as_bytes());
+                    commands.push(b' ');
+                }
+                let mut texts = text_buffer.lock();
+                for text in &entry.texts {
+                    texts.extend_from_slice(text.as_bytes());
+                }
+            }
+            if dispatch_fifo_request(&mut state, &from_editor).is_none() {
+                if let Some(err) = state.error.take() {
+                    print(format!("Failed to parse editor request: {}", err));
+                }
+            }
+            for mut request in editor_requests.try_iter() {
+                // Synchronous requests are replayed like asynchronous ones, and the recorded
+                // response fifo must not be written to.
+                if let Some(response_fifo) = request.response_fifo.take() {
+                    response_fifo.disarm();
+                }
+                print(format!("From editor: {}", request.method));
+                if process_editor_request(ctx, request).is_break() {
+                    drain(ctx, &mut replayed_ids);
+                    return;
+                }
+            }
+        } else if let Some(kind) = entry.kind.strip_prefix("receive-") {
+            let (Some(server_name), Some(message)) = (entry.server, entry.message) else {
+                continue;
+            };
+            let message = if kind == "response" {
+                serde_json::from_value(message).map(ServerMessage::Response)
+            } else {
+                serde_json::from_value(message).map(ServerMessage::Request)
+            };
+            let Ok(message) = message else {
+                print(format!(
+                    "Invalid message from server {} in trace",
+                    server_name
+                ));
+                continue;
+            };
+            deferred.extend(inject(ctx, &replayed_ids, server_name, message));
+        } else {
+            continue;
+        }
+        drain(ctx, &mut replayed_ids);
+        // Earlier responses may belong to a request that was sent just now.
+        for (server_name, message) in mem::take(&mut deferred) {
+            deferred.extend(inject(ctx, &replayed_ids, server_name, message));
+            drain(ctx, &mut replayed_ids);
+        }
+    }
+    for (server_name, _) in deferred {
+        print(format!(
+            "Could not replay a message from server {}: no such server or request",
+            server_name
+        ));
+    }
+}
+
+/// Handle a message from a language server: dispatch requests and notifications,
+/// and pass responses to the callbacks waiting for them.
+fn process_server_message(
+    ctx: &mut Context,
+    server_id: ServerId,
+    msg: ServerMessage,
+) -> ControlFlow<()> {
+    match msg {
+        ServerMessage::Request(call) => match call {
+            Call::MethodCall(request) => {
+                dispatch_server_request(server_id, EditorMeta::default(), request, ctx);
+            }
+            Call::Notification(notification) => {
+                dispatch_server_notification(
+                    server_id,
+                    EditorMeta::default(),
+                    &notification.method,
+                    notification.params,
+                    ctx,
+                );
+            }
+            Call::Invalid { id } => {
+                error!(
+                    ctx.to_editor(),
+                    "Invalid call from language server: {:?}", id
+                );
+            }
+        },
+        ServerMessage::Response(output) => {
+            match output {
+                Output::Success(success) => {
+                    if let Some(PendingResponse {
+                        meta,
+                        method,
+                        batch_id,
+                        canceled,
+                        sent_at,
+                        ..
+                    }) = ctx.response_waitlist.remove(&success.id)
+                    {
+                        if canceled {
+                            return ControlFlow::Continue(());
+                        }
+                        record_latency(ctx, server_id, method, sent_at);
+                        remove_outstanding_request(
+                            server_id,
+                            ctx,
+                            method,
+                            meta.buffile.clone(),
+                            meta.client.clone(),
+                            &success.id,
+                        );
+                        if let Some((mut vals, callback)) = ctx.batches.remove(&batch_id) {
+                            if let Some(batch_seq) = ctx.batch_sizes.remove(&batch_id) {
+                                vals.push((server_id, success.result));
+                                let batch_size: usize = batch_seq.values().sum();
+
+                                if vals.len() >= batch_size {
+                                    callback(ctx, meta, vals);
+                                    if ctx.is_exiting {
+                                        return ControlFlow::Break(());
+                                    }
+                                } else {
+                                    ctx.batch_sizes.insert(batch_id, batch_seq);
+                                    ctx.batches.insert(batch_id, (vals, callback));
+                                }
+                            }
+                        }
+                    } else if gave_up_on_request(ctx, &success.id) {
+                        debug!(
+                            ctx.to_editor(),
+                            "Ignoring late response to {:?} from server {}",
+                            success.id,
+                            &ctx.server(server_id).name
+                        );
+                    } else {
+                        error!(ctx.to_editor(), "Id {:?} is not in waitlist!", success.id);
+                    }
+                }
+                Output::Failure(failure) => {
+                    if let Some(request) = ctx.response_waitlist.remove(&failure.id) {
+                        if request.canceled {
+                            return ControlFlow::Continue(());
+                        }
+                        record_latency(ctx, server_id, request.method, request.sent_at);
+                        remove_outstanding_request(
+                            server_id,
+                            ctx,
+                            request.method,
+                            request.meta.buffile.clone(),
+                            request.meta.client.clone(),
+                            &failure.id,
+                        );
+                        let outdated = failure.error.code
+                            == ErrorCode::ServerError(CONTENT_MODIFIED)
+                            || failure.error.code == ErrorCode::ServerError(SERVER_CANCELLED);
+                        if outdated
+                            && can_retry(request.method)
+                            && request.retries < MAX_REQUEST_RETRIES
+                            && ctx.batches.contains_key(&request.batch_id)
+                        {
+                            debug!(
+                                ctx.to_editor(),
+                                "Server {} gave up on {} ({}), sending it again",
+                                &ctx.server(server_id).name,
+                                request.method,
+                                failure.error.message
+                            );
+                            retry_request(ctx, request);
+                            return ControlFlow::Continue(());
+                        }
+                        let PendingResponse {
+                            meta,
+                            method,
+                            batch_id,
+                            ..
+                        } = request;
+                        if outdated {
+                            debug!(
+                                ctx.to_editor(),
+                                "Error response from server {}: {:?}",
+                                &ctx.server(server_id).name,
+                                failure
+                            );
+                        } else {
+                            error!(
+                                ctx.to_editor(),
+                                "Error response from server {}: {:?}",
+                                &ctx.server(server_id).name,
+                                failure
+                            );
+                        }
+                        if let Some((vals, callback)) = ctx.batches.remove(&batch_id) {
+                            if let Some(mut batch_seq) = ctx.batch_sizes.remove(&batch_id) {
+                                batch_seq.remove(&server_id);
+
+                                // We con only keep going if there are still other servers to respond.
+                                // Otherwise, skip the following block and handle failure.
+                                if !batch_seq.is_empty() {
+                                    // Remove this failing language server from the batch, allowing
+                                    // working ones to still be handled.
+                                    let vals: Vec<_> =
+                                        vals.into_iter().filter(|(s, _)| *s != server_id).collect();
+
+                                    // Scenario: this failing server is holding back the response handling
+                                    // for all other servers, which already responded successfully.
+                                    let batch_size: usize = batch_seq.values().sum();
+                                    if vals.len() >= batch_size {
+                                        callback(ctx, meta, vals);
+                                        if ctx.is_exiting {
+                                            return ControlFlow::Break(());
+                                        }
+                                    } else {
+                                        // Re-insert the batch, as we have no business with it at the moment,
+                                        // since not all servers have completely responded.
+                                        ctx.batch_sizes.insert(batch_id, batch_seq);
+                                        ctx.batches.insert(batch_id, (vals, callback));
+                                    }
+
+                                    return ControlFlow::Continue(());
+                                }
+                            }
+                        }
+                        match failure.error.code {
+                            _ if outdated || method == request::CodeActionRequest::METHOD => {}
+                            code => {
+                                let msg = match code {
+                                    ErrorCode::MethodNotFound => format!(
+                                        "language server {} doesn't support method {}",
+                                        &ctx.server(server_id).name,
+                                        method
+                                    ),
+                                    _ => format!(
+                                        "language server {} error: {}",
+                                        &ctx.server(server_id).name,
+                                        editor_quote(&failure.error.message)
+                                    ),
+                                };
+                                ctx.show_error(meta, msg);
+                            }
+                        }
+                    } else if gave_up_on_request(ctx, &failure.id) {
+                        debug!(
+                            ctx.to_editor(),
+                            "Ignoring late error response from server {}: {:?}",
+                            &ctx.server(server_id).name,
+                            failure
+                        );
+                    } else {
+                        error!(
+                            ctx.to_editor(),
+                            "Error response from server {}: {:?}",
+                            &ctx.server(server_id).name,
+                            failure
+                        );
+                        error!(ctx.to_editor(), "Id {:?} is not in waitlist!", failure.id);
+                    }
+                }
+            }
+        }
+    }
+    ControlFlow::Continue(())
+}
+
 pub fn process_editor_request(ctx: &mut Context, mut request: EditorRequest) -> ControlFlow<()> {
     if let Some(flow) = route_request(ctx, &mut request.meta, &request.method) {
         return flow;
@@ -1288,6 +1766,16 @@ fn stop_session(ctx: &mut Context) {
     };
     let flow = process_editor_request(ctx, request);
     assert!(flow.is_break());
+    if let Some(path) = &ctx.config.server.metrics_file {
+        if let Err(err) = fs::write(path, ctx.metrics.json_lines()) {
+            error!(
+                ctx.to_editor(),
+                "Failed to write metrics to {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
     debug!(ctx.to_editor(), "Exit all servers");
 }
 
@@ -1326,7 +1814,15 @@ fn route_request(
         );
         return Some(ControlFlow::Break(()));
     }
-    if request_method == notification::Exit::METHOD {
+    // These are not about any buffer, so they need no server.
+    if matches!(
+        request_method,
+        notification::Exit::METHOD
+            | "kakoune/diagnostics-buffer-closed"
+            | "kakoune/status"
+            | "kakoune/metrics"
+            | "kakoune/did-change-option"
+    ) {
         return None;
     }
     if !meta.session.is_empty() && &meta.session != ctx.session() {
@@ -1338,14 +1834,13 @@ fn route_request(
         );
         return Some(ControlFlow::Break(()));
     }
-    if !meta.buffile.starts_with('/') {
-        report_error_no_server_configured(
-            ctx,
-            meta,
-            request_method,
-            "not supported in scratch buffers",
-        );
-        return Some(ControlFlow::Continue(()));
+    if let Some(document) = ctx.documents.get_mut(&meta.buffile) {
+        if let Some(server_id) = document.content_provider {
+            // Virtual documents are read-only, so the buffer can never be ahead of us.
+            document.version = meta.version;
+            meta.servers.push(server_id);
+            return None;
+        }
     }
     if ctx.buffer_tombstones.contains(&meta.buffile) {
         report_error_no_server_configured(
@@ -1377,6 +1872,12 @@ fn route_request(
     #[allow(deprecated)]
     let legacy_cfg = ctx.legacy_filetypes.get(&meta.filetype);
     let server_addresses: Vec<(ServerName, RootPath)>;
+    // Scratch buffers have no path to search for a project root, so serve them from the
+    // current working directory.
+    let scratch_root = is_scratch_buffer(&meta.buffile)
+        .then(std::env::current_dir)
+        .and_then(Result::ok)
+        .map(|cwd| cwd.to_string_lossy().into_owned());
     if is_using_legacy_toml(&ctx.config) {
         #[allow(deprecated)]
         let Some((language_id, servers)) = legacy_cfg
@@ -1397,8 +1898,12 @@ fn route_request(
                 .language_server
                 .get_mut(server_name_for_lookup(&ctx.config, language_id, server_name).as_ref())
                 .unwrap();
-            server_config.root =
-                find_project_root(&to_editor, language_id, &server_config.roots, &meta.buffile);
+            server_config.root = match &scratch_root {
+                Some(root) => root.clone(),
+                None => {
+                    find_project_root(&to_editor, language_id, &server_config.roots, &meta.buffile)
+                }
+            };
         }
 
         #[allow(deprecated)]
@@ -1442,6 +1947,10 @@ fn route_request(
             return Some(ControlFlow::Continue(()));
         };
         for (server_name, server) in &mut meta.language_server {
+            if let Some(root) = &scratch_root {
+                server.root = root.clone();
+                continue;
+            }
             if !server.root.is_empty() && !server.root_globs.is_empty() {
                 let msg = "cannot specify both root and root_globs";
                 ctx.show_error(mem::take(meta), msg);
@@ -1523,13 +2032,7 @@ fn route_request(
             return Some(ControlFlow::Continue(()));
         }
 
-        let server_transport = match language_server_transport::start(
-            ctx.to_editor(),
-            server_name.clone(),
-            server_command,
-            &server_config.args,
-            &server_config.envs,
-        ) {
+        let server_transport = match start_server_transport(ctx, &server_name, server_config) {
             Ok(ls) => ls,
             Err(err) => {
                 ctx.server_tombstones.insert(server_command.to_string());
@@ -1559,6 +2062,11 @@ fn route_request(
             capabilities: None,
             settings: None,
             workaround_eslint: server_config.workaround_eslint.unwrap_or_default(),
+            diagnostic_filter: server_config.diagnostic_filter.clone(),
+            start_meta: meta.clone(),
+            started_at: Instant::now(),
+            crash_count: 0,
+            restart_at: None,
         };
         ctx.language_servers.insert(server_id, server_settings);
         ctx.route_cache.insert((server_name, root), server_id);
@@ -1570,6 +2078,438 @@ fn route_request(
     None
 }
 
+/// Whether a response is for a recent request that we no longer wait for because it
+/// timed out.
+fn gave_up_on_request(ctx: &Context, id: &Id) -> bool {
+    ctx.timed_out_requests.contains(id)
+}
+
+/// Give up on requests that took longer than their timeout, and handle the responses of
+/// the same batch that did arrive.
+fn time_out_requests(ctx: &mut Context) -> ControlFlow<()> {
+    let now = Instant::now();
+    let expired: Vec<Id> = ctx
+        .response_waitlist
+        .iter()
+        .filter(|(_, request)| {
+            !request.canceled
+                && ctx
+                    .request_timeout(request.method)
+                    .is_some_and(|timeout| request.sent_at + timeout <= now)
+        })
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in expired {
+        let request = &ctx.response_waitlist[&id];
+        let server_id = request.server_id;
+        let method = request.method;
+        let batch_id = request.batch_id;
+        let meta = request.meta.clone();
+        ctx.cancel(server_id, id.clone());
+        // The server might never answer, so don't wait for it.
+        ctx.response_waitlist.remove(&id);
+        if ctx.timed_out_requests.len() == TIMED_OUT_REQUESTS_LENGTH {
+            ctx.timed_out_requests.pop_front();
+        }
+        ctx.timed_out_requests.push_back(id.clone());
+        let server_name = ctx.server(server_id).name.clone();
+        ctx.metrics.histogram(&server_name, method).timeouts += 1;
+        remove_outstanding_request(
+            server_id,
+            ctx,
+            method,
+            meta.buffile.clone(),
+            meta.client.clone(),
+            &id,
+        );
+        if let Some((vals, callback)) = ctx.batches.remove(&batch_id) {
+            if let Some(mut batch_seq) = ctx.batch_sizes.remove(&batch_id) {
+                if let Some(count) = batch_seq.get_mut(&server_id) {
+                    *count -= 1;
+                    if *count == 0 {
+                        batch_seq.remove(&server_id);
+                    }
+                }
+                let batch_size: usize = batch_seq.values().sum();
+                if vals.len() < batch_size {
+                    ctx.batch_sizes.insert(batch_id, batch_seq);
+                    ctx.batches.insert(batch_id, (vals, callback));
+                } else if !vals.is_empty() {
+                    callback(ctx, meta.clone(), vals);
+                    if ctx.is_exiting {
+                        return ControlFlow::Break(());
+                    }
+                }
+                // Otherwise, dropping the callback releases any response fifo.
+            }
+        }
+        ctx.show_error(
+            meta,
+            format!("language server {} timed out on {}", server_name, method),
+        );
+    }
+    ControlFlow::Continue(())
+}
+
+/// How often to send a request again when the server says the document changed while it was busy.
+const MAX_REQUEST_RETRIES: u32 = 3;
+
+/// Whether a request can be sent again with the same params for a newer version of the
+/// document. Requests about a position or range would need new params, so the editor has
+/// to send them again.
+fn can_retry(method: &str) -> bool {
+    matches!(
+        method,
+        request::SemanticTokensFullRequest::METHOD
+            | request::DocumentSymbolRequest::METHOD
+            | request::CodeLensRequest::METHOD
+    )
+}
+
+/// Send a request again after the server gave up on it, for the current version of the document.
+fn retry_request(ctx: &mut Context, mut request: PendingResponse) {
+    if let Some(document) = ctx.documents.get(&request.meta.buffile) {
+        request.meta.version = document.version;
+    }
+    request.retries += 1;
+    request.sent_at = Instant::now();
+    ctx.send_request(request);
+}
+
+fn record_latency(ctx: &mut Context, server_id: ServerId, method: &'static str, sent_at: Instant) {
+    let latency = sent_at.elapsed();
+    let server_name = &ctx.language_servers[&server_id].name;
+    let threshold = ctx.config.server.slow_request_threshold;
+    if threshold != 0 && latency > Duration::from_millis(threshold) {
+        warn!(
+            &ctx.to_editor,
+            "Language server {server_name} took {}ms to respond to {method}",
+            latency.as_millis()
+        );
+    }
+    ctx.metrics.histogram(server_name, method).record(latency);
+}
+
+fn start_server_transport(
+    ctx: &Context,
+    server_name: &ServerName,
+    server_config: &LanguageServerConfig,
+) -> Result<LanguageServerTransport, String> {
+    if ctx.replaying {
+        return Ok(language_server_transport::stub(ctx.to_editor()));
+    }
+    let server_command = server_config.command.as_ref().unwrap_or(server_name);
+    language_server_transport::start(
+        ctx.to_editor(),
+        server_name.clone(),
+        // With only an address, connect to an already running server.
+        (server_config.address.is_none() || server_config.command.is_some())
+            .then_some(server_command.as_str()),
+        &server_config.args,
+        &server_config.envs,
+        server_config.address.as_deref(),
+        Duration::from_secs(ctx.config.server.shutdown_timeout),
+    )
+}
+
+/// How many times to restart a language server that keeps crashing.
+const MAX_SERVER_RESTARTS: u32 = 5;
+/// Wait before the first restart, doubled with every crash in a row.
+const SERVER_RESTART_DELAY: Duration = Duration::from_secs(1);
+/// A server that crashes after running this long is not considered to be crashing in a row.
+const SERVER_STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+/// Whether a server is up, as opposed to waiting to be restarted or given up on after crashing.
+fn is_running(server: &ServerSettings) -> bool {
+    server.restart_at.is_none() && server.crash_count <= MAX_SERVER_RESTARTS
+}
+
+/// The servers whose messages to wait for. Crashed servers have nothing to say, and their
+/// disconnected receivers would always be ready.
+fn running_servers(ctx: &Context) -> (Vec<ServerId>, Vec<&Receiver<ServerMessage>>) {
+    ctx.language_servers
+        .iter()
+        .filter(|(_, server)| is_running(server))
+        .map(|(&server_id, server)| (server_id, server.transport.from_lang_server.receiver()))
+        .unzip()
+}
+
+fn handle_server_crash(ctx: &mut Context, server_id: ServerId) {
+    let server = ctx.server(server_id);
+    let reason =
+        server
+            .transport
+            .connect_error()
+            .unwrap_or_else(|| match server.transport.exit_status() {
+                Some(status) => format!("exited ({status})"),
+                None => "closed the connection".to_string(),
+            });
+    let server = ctx.language_servers.get_mut(&server_id).unwrap();
+    // Park requests until the server is initialized again.
+    server.capabilities = None;
+    abandon_requests(ctx, server_id);
+    schedule_restart(ctx, server_id, &reason);
+}
+
+/// Give up on the requests that a crashed server will not answer, letting their batches
+/// complete with the responses of the other servers.
+fn abandon_requests(ctx: &mut Context, server_id: ServerId) {
+    let ids: Vec<_> = ctx
+        .response_waitlist
+        .iter()
+        .filter(|(_, request)| request.server_id == server_id)
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in ids {
+        let PendingResponse { meta, batch_id, .. } = ctx.response_waitlist.remove(&id).unwrap();
+        let (Some((vals, callback)), Some(mut batch_seq)) = (
+            ctx.batches.remove(&batch_id),
+            ctx.batch_sizes.remove(&batch_id),
+        ) else {
+            continue;
+        };
+        batch_seq.remove(&server_id);
+        if batch_seq.is_empty() {
+            continue;
+        }
+        let vals: Vec<_> = vals.into_iter().filter(|(s, _)| *s != server_id).collect();
+        let batch_size: usize = batch_seq.values().sum();
+        if vals.len() >= batch_size {
+            callback(ctx, meta, vals);
+        } else {
+            ctx.batch_sizes.insert(batch_id, batch_seq);
+            ctx.batches.insert(batch_id, (vals, callback));
+        }
+    }
+    ctx.outstanding_requests
+        .retain(|(request_server_id, ..), _| *request_server_id != server_id);
+}
+
+fn schedule_restart(ctx: &mut Context, server_id: ServerId, reason: &str) {
+    let server = ctx.language_servers.get_mut(&server_id).unwrap();
+    if server.started_at.elapsed() >= SERVER_STABLE_UPTIME {
+        server.crash_count = 0;
+    }
+    server.crash_count += 1;
+    let server_name = server.name.clone();
+    let start_meta = server.start_meta.clone();
+    if server.crash_count > MAX_SERVER_RESTARTS {
+        error!(
+            ctx.to_editor(),
+            "language server {server_name} {reason}, giving up after {MAX_SERVER_RESTARTS} restarts"
+        );
+        // New requests will find the server in the tombstones and disable it in their buffers.
+        let server_command = ctx
+            .server_config(&start_meta, &server_name)
+            .and_then(|server_config| server_config.command.clone())
+            .unwrap_or_else(|| server_name.clone());
+        ctx.server_tombstones.insert(server_command);
+        ctx.route_cache
+            .retain(|_, route_server_id| *route_server_id != server_id);
+        ctx.pending_requests
+            .retain(|request| !request.meta.servers.contains(&server_id));
+    } else {
+        let delay = SERVER_RESTART_DELAY * 2_u32.pow(server.crash_count - 1);
+        server.restart_at = Some(Instant::now() + delay);
+        error!(
+            ctx.to_editor(),
+            "language server {server_name} {reason}, restarting in {}s",
+            delay.as_secs()
+        );
+    }
+    update_server_status(ctx);
+}
+
+fn restart_servers(ctx: &mut Context) {
+    let now = Instant::now();
+    let due: Vec<_> = ctx
+        .language_servers
+        .iter()
+        .filter(|(_, server)| {
+            server
+                .restart_at
+                .is_some_and(|restart_at| restart_at <= now)
+        })
+        .map(|(&server_id, _)| server_id)
+        .collect();
+    for server_id in due {
+        let server = ctx.language_servers.get_mut(&server_id).unwrap();
+        server.restart_at = None;
+        let meta = server.start_meta.clone();
+        let server_name = server.name.clone();
+        info!(ctx.to_editor(), "Restarting language server {server_name}");
+        let transport = match ctx.server_config(&meta, &server_name) {
+            Some(server_config) => start_server_transport(ctx, &server_name, server_config),
+            None => Err(format!(
+                "language server {server_name} is no longer configured"
+            )),
+        };
+        match transport {
+            Ok(transport) => {
+                let server = ctx.language_servers.get_mut(&server_id).unwrap();
+                server.transport = transport;
+                server.started_at = Instant::now();
+                update_server_status(ctx);
+                initialize(meta, ctx, vec![server_id]);
+            }
+            Err(err) => schedule_restart(ctx, server_id, &format!("failed to restart: {err}")),
+        }
+    }
+}
+
+/// Show crashed servers in the modeline.
+fn update_server_status(ctx: &Context) {
+    let status = ctx
+        .language_servers
+        .values()
+        .filter_map(|server| {
+            if server.restart_at.is_some() {
+                Some(format!("⚠{} restarting", server.name))
+            } else if server.crash_count > MAX_SERVER_RESTARTS {
+                Some(format!("⚠{} down", server.name))
+            } else {
+                None
+            }
+        })
+        .join(" ");
+    ctx.exec(
+        EditorMeta::default(),
+        format!(
+            "set-option global lsp_modeline_server_status {}",
+            editor_quote(&status)
+        ),
+    );
+}
+
+/// Apply new trace levels, and tell the running servers whose level changed.
+fn set_server_trace(ctx: &mut Context, server_trace: HashMap<ServerName, TraceValue>) {
+    let old_server_trace = mem::replace(&mut ctx.config.server.server_trace, server_trace);
+    let servers: Vec<_> = ctx
+        .language_servers
+        .iter()
+        .filter(|(_, server)| server.capabilities.is_some() && server.restart_at.is_none())
+        .filter(|(_, server)| {
+            old_server_trace
+                .get(&server.name)
+                .copied()
+                .unwrap_or_default()
+                != ctx.server_trace(&server.name)
+        })
+        .map(|(&server_id, server)| (server_id, ctx.server_trace(&server.name)))
+        .collect();
+    for (server_id, value) in servers {
+        ctx.notify::<notification::SetTrace>(server_id, SetTraceParams { value });
+    }
+}
+
+/// Send the shutdown request and exit notification to all servers, and wait for them to exit.
+/// Servers that take longer than the shutdown timeout are terminated.
+fn shutdown_servers(ctx: &mut Context) {
+    let shutdown_timeout = Duration::from_secs(ctx.config.server.shutdown_timeout);
+    let deadline = Instant::now() + shutdown_timeout;
+    // Crashed servers are already gone.
+    let running: Vec<_> = ctx
+        .language_servers
+        .iter()
+        .filter(|(_, server)| is_running(server))
+        .map(|(&server_id, server)| (server_id, server.capabilities.is_some()))
+        .collect();
+
+    // Only initialized servers may be sent a shutdown request.
+    let mut pending = HashMap::new();
+    for &(server_id, initialized) in &running {
+        if !initialized {
+            continue;
+        }
+        let id = ctx.next_request_id();
+        debug!(
+            ctx.to_editor(),
+            "Sending shutdown request to server {}",
+            ctx.server(server_id).name
+        );
+        let call = MethodCall {
+            jsonrpc: Some(jsonrpc_core::Version::V2),
+            id: id.clone(),
+            method: request::Shutdown::METHOD.into(),
+            params: Params::None,
+        };
+        if ctx
+            .server(server_id)
+            .transport
+            .to_lang_server
+            .sender()
+            .send(ServerMessage::Request(Call::MethodCall(call)))
+            .is_ok()
+        {
+            pending.insert(server_id, id);
+        }
+    }
+    while !pending.is_empty() {
+        let server_ids: Vec<_> = pending.keys().copied().collect();
+        let receivers: Vec<_> = server_ids
+            .iter()
+            .map(|server_id| ctx.server(*server_id).transport.from_lang_server.receiver())
+            .collect();
+        let mut sel = Select::new();
+        for rx in &receivers {
+            sel.recv(rx);
+        }
+        let Ok(op) = sel.select_deadline(deadline) else {
+            warn!(
+                ctx.to_editor(),
+                "Language servers did not respond to shutdown request in time: {}",
+                server_ids
+                    .iter()
+                    .map(|server_id| &ctx.server(*server_id).name)
+                    .join(", ")
+            );
+            break;
+        };
+        let index = op.index();
+        let server_id = server_ids[index];
+        match op.recv(receivers[index]) {
+            // Ignore anything else the server has to say.
+            Ok(ServerMessage::Response(output)) if output.id() != &pending[&server_id] => (),
+            Ok(ServerMessage::Request(_)) => (),
+            Ok(ServerMessage::Response(_)) | Err(_) => {
+                pending.remove(&server_id);
+            }
+        }
+    }
+
+    for &(server_id, _) in &running {
+        debug!(
+            ctx.to_editor(),
+            "Sending exit notification to server {}",
+            ctx.server(server_id).name
+        );
+        ctx.notify::<notification::Exit>(server_id, ());
+    }
+
+    // Stop all servers at once, so that one that doesn't exit does not hold up the others.
+    let servers = mem::take(&mut ctx.language_servers);
+    let count = servers.len();
+    let (done_tx, done_rx) = crossbeam_channel::unbounded();
+    for (_, server) in servers {
+        let done_tx = done_tx.clone();
+        std::thread::spawn(move || {
+            drop(server);
+            let _ = done_tx.send(());
+        });
+    }
+    // Once terminated, servers may still take a moment to release their output.
+    let deadline = Instant::now() + shutdown_timeout + language_server_transport::KILL_TIMEOUT * 2;
+    for _ in 0..count {
+        if done_rx.recv_deadline(deadline).is_err() {
+            warn!(
+                ctx.to_editor(),
+                "Language servers are still shutting down, exiting anyway"
+            );
+            break;
+        }
+    }
+}
+
 fn report_error_no_server_configured(
     ctx: &mut Context,
     meta: &EditorMeta,
@@ -1738,7 +2678,10 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) -> Control
             call_hierarchy::call_hierarchy_prepare(meta, params.unbox(), ctx);
         }
         request::CodeLensRequest::METHOD => {
-            text_document_code_lens(meta, ctx);
+            text_document_code_lens(meta, params.unbox(), ctx);
+        }
+        "kakoune/code-lens-resolve" => {
+            code_lens::resolve_visible_code_lenses(meta, params.unbox(), ctx);
         }
         request::Completion::METHOD => {
             completion::text_document_completion(meta, params.unbox(), ctx);
@@ -1774,15 +2717,7 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) -> Control
             goto::text_document_references(meta, params.unbox(), ctx);
         }
         notification::Exit::METHOD => {
-            for server in ctx.language_servers.values() {
-                debug!(
-                    ctx.to_editor(),
-                    "Sending exit notification to server {}", server.name
-                );
-            }
-            for server_id in ctx.language_servers.keys().cloned().collect_vec() {
-                ctx.notify::<notification::Exit>(server_id, ());
-            }
+            shutdown_servers(ctx);
             return ControlFlow::Break(());
         }
 
@@ -1824,6 +2759,12 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) -> Control
         "kakoune/inlay-hint-apply-nearest" => {
             inlay_hints::inlay_hint_apply(meta, params.unbox(), ctx);
         }
+        "kakoune/inlay-hint-goto" => {
+            inlay_hints::inlay_hint_goto(meta, params.unbox(), ctx);
+        }
+        "kakoune/inlay-hint-hover" => {
+            inlay_hints::inlay_hint_hover(meta, params.unbox(), ctx);
+        }
         "kakoune/inlay-hint-apply-selected" => {
             inlay_hints::inlay_hint_apply(meta, params.unbox(), ctx);
         }
@@ -1859,9 +2800,30 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) -> Control
         "textDocument/diagnostics" => {
             diagnostics::editor_diagnostics(meta, params.unbox(), ctx);
         }
+        "kakoune/diagnostics-buffer-closed" => {
+            diagnostics::diagnostics_buffer_closed(ctx);
+        }
+        "kakoune/diagnostic-open-docs" => {
+            diagnostics::diagnostic_open_docs(meta, params.unbox(), ctx);
+        }
+        "kakoune/diagnostic-related" => {
+            diagnostics::diagnostic_related(meta, params.unbox(), ctx);
+        }
+        "kakoune/diagnostics-export" => {
+            diagnostics::export_diagnostics(meta, params.unbox(), ctx);
+        }
         "capabilities" => {
             capabilities::capabilities(meta, ctx);
         }
+        "kakoune/status" => {
+            status::status(meta, ctx);
+        }
+        "kakoune/metrics" => {
+            status::metrics(meta, ctx);
+        }
+        "kakoune/did-change-option" => {
+            set_server_trace(ctx, params.unbox());
+        }
         "apply-workspace-edit" => {
             if let Some(&server_id) = meta.servers.first() {
                 workspace::apply_edit_from_editor(
@@ -2017,7 +2979,7 @@ fn dispatch_server_request(
             Ok(serde_json::Value::Null)
         }
         request::WorkDoneProgressCreate::METHOD => {
-            progress::work_done_progress_create(request.params, ctx)
+            progress::work_done_progress_create(server_id, request.params, ctx)
         }
         request::WorkspaceConfiguration::METHOD => {
             workspace::configuration(meta, request.params, server_id, ctx)
@@ -2066,7 +3028,7 @@ fn dispatch_server_notification(
 ) {
     match method {
         notification::Progress::METHOD => {
-            progress::dollar_progress(meta, params, ctx);
+            progress::dollar_progress(server_id, meta, params, ctx);
         }
         notification::PublishDiagnostics::METHOD => {
             diagnostics::publish_diagnostics(server_id, params, ctx);
@@ -2109,6 +3071,51 @@ fn dispatch_server_notification(
                 ),
             );
         }
+        notification::LogTrace::METHOD => {
+            let params: LogTraceParams = params
+                .parse()
+                .expect("Failed to parse LogTraceParams params");
+            let mut message = params.message;
+            message.push('\n');
+            if let Some(verbose) = params.verbose {
+                message.push_str(&verbose);
+                message.push('\n');
+            }
+            ctx.exec(
+                meta,
+                format!(
+                    "evaluate-commands -verbatim -try-client '{}' lsp-show-trace {} {}",
+                    LAST_CLIENT
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .map(|client| client.as_str())
+                        .unwrap_or_default(),
+                    editor_quote(&ctx.server(server_id).name),
+                    editor_quote(&message)
+                ),
+            );
+        }
+        notification::Cancel::METHOD => {
+            let params: CancelParams = params.parse().expect("Failed to parse CancelParams params");
+            let id = match params.id {
+                NumberOrString::Number(id) => match u64::try_from(id) {
+                    Ok(id) => Id::Num(id),
+                    // Not one of ours, we only send non-negative ids.
+                    Err(_) => {
+                        debug!(
+                            ctx.to_editor(),
+                            "Server {} canceled request with invalid id {}",
+                            &ctx.server(server_id).name,
+                            id
+                        );
+                        return;
+                    }
+                },
+                NumberOrString::String(id) => Id::Str(id),
+            };
+            show_message::cancel_message_request(meta, server_id, id, ctx);
+        }
         "telemetry/event" => {
             debug!(ctx.to_editor(), "{:?}", params);
         }
@@ -2132,13 +3139,10 @@ fn dispatch_server_notification(
 /// most of the time in `if buffile.is_empty() || ctx.documents.contains_key(buffile)` condition.
 fn ensure_did_open(request: &EditorRequest, ctx: &mut Context) {
     let buffile = &request.meta.buffile;
-    if buffile.is_empty() || ctx.documents.contains_key(buffile) {
+    if buffile.is_empty() || ctx.documents.contains_key(buffile) || request.meta.servers.is_empty()
+    {
         return;
     };
-    if !buffile.starts_with('/') {
-        assert_eq!(&request.method, notification::Exit::METHOD);
-        return;
-    }
     if request.method == notification::DidChangeTextDocument::METHOD {
         let params: &TextDocumentDidChangeParams = request.params.downcast_ref();
         text_document_did_open(
@@ -2150,6 +3154,10 @@ fn ensure_did_open(request: &EditorRequest, ctx: &mut Context) {
         );
         return;
     }
+    if is_scratch_buffer(buffile) {
+        // There is no file to read, the next textDocument/didChange will open it.
+        return;
+    }
     let draft = read_document(buffile)
         .map_err(|err| {
             debug!(
@@ -2164,3 +3172,607 @@ fn ensure_did_open(request: &EditorRequest, ctx: &mut Context) {
         ctx,
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::context_with_stub_server;
+    use crate::editor_transport::mock_to_editor;
+    use jsonrpc_core::Version;
+    use lsp_types::error_codes::REQUEST_CANCELLED;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn tokenizer(input: &[u8]) -> Tokenizer {
+        let tokenizer = Tokenizer::default();
+        tokenizer.input.lock().extend_from_slice(input);
+        tokenizer
+    }
+
+    fn no_more_input<'a>(
+        _buffer: MutexGuard<'a, Vec<u8>>,
+        _notifier: &Condvar,
+    ) -> Option<MutexGuard<'a, Vec<u8>>> {
+        None
+    }
+
+    fn random_string(rng: &mut StdRng) -> String {
+        let len = rng.gen_range(0..20);
+        (0..len)
+            .map(|_| *b" '\\\"a%\n".get(rng.gen_range(0..8)).unwrap_or(&b'x') as char)
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_quoted_tokens() {
+        let mut tokenizer = tokenizer(br"'a b' 'it'\''s' '' \x ");
+        let mut tokens = vec![];
+        while let Some(token) = tokenizer.read_token(no_more_input) {
+            tokens.push(token.unwrap());
+        }
+        assert_eq!(tokens, ["a b", "it's", "", "x"]);
+    }
+
+    #[test]
+    fn tokenize_quoted_random_strings() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let tokens: Vec<String> = (0..rng.gen_range(0..5))
+                .map(|_| random_string(&mut rng))
+                .collect();
+            let input: String = tokens
+                .iter()
+                .map(|token| quote_token(token) + " ")
+                .collect();
+            let mut tokenizer = tokenizer(input.as_bytes());
+            let mut read = vec![];
+            while let Some(token) = tokenizer.read_token(no_more_input) {
+                read.push(token.unwrap());
+            }
+            assert_eq!(read, tokens, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn tokenize_random_bytes() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..50);
+            let input: Vec<u8> = (0..len)
+                .map(|_| *b" '\\a\n\xff".get(rng.gen_range(0..7)).unwrap_or(&0))
+                .collect();
+            let mut tokenizer = tokenizer(&input);
+            // Every token or error consumes input.
+            let mut reads = 0;
+            while tokenizer.read_token(no_more_input).is_some() {
+                reads += 1;
+                assert!(reads <= len, "input: {:?}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn skip_random_bytes() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..50);
+            let mut input: Vec<u8> = (0..len)
+                .map(|_| *b" '\\a\n\xff".get(rng.gen_range(0..7)).unwrap_or(&0))
+                .collect();
+            // One more space, in case the garbage ends in a backslash.
+            input.extend_from_slice(b"  'sess' 'client' ");
+            let mut tokenizer = tokenizer(&input);
+            loop {
+                match tokenizer.read_token(no_more_input).unwrap() {
+                    Ok(token) if token == "sess" => break,
+                    Ok(_) => (),
+                    Err(_) => {
+                        assert_eq!(tokenizer.skip_to("sess", no_more_input), Some(()));
+                    }
+                }
+            }
+            assert_eq!(
+                tokenizer.read_token(no_more_input),
+                Some(Ok("client".to_string())),
+                "input: {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn skip_to_next_request() {
+        let mut tokenizer = tokenizer(b"'x' stray' quote 'sess' 'client' ");
+        assert_eq!(
+            tokenizer.read_token(no_more_input),
+            Some(Ok("x".to_string()))
+        );
+        assert!(tokenizer.read_token(no_more_input).unwrap().is_err());
+        assert_eq!(tokenizer.skip_to("sess", no_more_input), Some(()));
+        assert_eq!(
+            tokenizer.read_token(no_more_input),
+            Some(Ok("sess".to_string()))
+        );
+        assert_eq!(
+            tokenizer.read_token(no_more_input),
+            Some(Ok("client".to_string()))
+        );
+        assert_eq!(tokenizer.skip_to("sess", no_more_input), None);
+    }
+
+    fn broken_request_input(text_line_count: usize, method: &str) -> String {
+        let text_line_count = text_line_count.to_string();
+        [
+            "sess",
+            "client",
+            &text_line_count,
+            "false",
+            "false",
+            "",
+            "0",
+            "",
+            "",
+            "",
+            "[]",
+            "",
+            "map-end",
+            method,
+        ]
+        .iter()
+        .map(|token| quote_token(token) + " ")
+        .collect()
+    }
+
+    fn broken_request_state(command: &str, text: &str) -> ParserState {
+        let command_buffer = SharedBuffer::default();
+        command_buffer.lock().extend_from_slice(command.as_bytes());
+        let text_buffer = SharedBuffer::default();
+        text_buffer.lock().extend_from_slice(text.as_bytes());
+        ParserState::new(
+            Arc::new(AtomicBool::new(true)),
+            mock_to_editor(),
+            "sess".to_string(),
+            command_buffer,
+            text_buffer,
+        )
+    }
+
+    #[test]
+    fn recover_from_broken_request() {
+        let input = broken_request_input(0, "no/such/method")
+            + "'unexpected' 'params' "
+            + &broken_request_input(0, "kakoune/status");
+        let mut state = broken_request_state(&input, "");
+        let (from_editor, editor_requests) = crossbeam_channel::unbounded();
+        assert!(dispatch_fifo_request(&mut state, &from_editor).is_none());
+        assert_eq!(
+            state.error.as_deref(),
+            Some("unexpected method no/such/method")
+        );
+        assert_eq!(state.recover(), Some(()));
+        assert!(dispatch_fifo_request(&mut state, &from_editor).is_some());
+        let request = editor_requests.try_recv().unwrap();
+        assert_eq!(request.method, "kakoune/status");
+        assert_eq!(
+            request.meta.client.as_ref().map(|client| client.as_str()),
+            Some("client")
+        );
+    }
+
+    #[test]
+    fn recover_from_broken_request_with_buffer_text() {
+        let input = broken_request_input(2, "no/such/method")
+            + &broken_request_input(1, "textDocument/didChange");
+        let mut state = broken_request_state(&input, "stale\ntext\nfresh text\n");
+        let (from_editor, editor_requests) = crossbeam_channel::unbounded();
+        assert!(dispatch_fifo_request(&mut state, &from_editor).is_none());
+        assert_eq!(state.recover(), Some(()));
+        assert!(dispatch_fifo_request(&mut state, &from_editor).is_some());
+        let request = editor_requests.try_recv().unwrap();
+        assert_eq!(request.method, "textDocument/didChange");
+        let params: &TextDocumentDidChangeParams = request.params.downcast_ref();
+        assert_eq!(params.draft, "fresh text\n");
+        assert!(state.text_buffer.lock().is_empty());
+    }
+
+    /// The messages that were sent to the stub server.
+    fn sent_to_server(ctx: &Context) -> Vec<ServerMessage> {
+        let receiver = ctx.server(0).transport.stub_receiver.as_ref().unwrap();
+        receiver.try_iter().collect()
+    }
+
+    fn sent_request_id(ctx: &Context) -> Id {
+        match &sent_to_server(ctx)[..] {
+            [ServerMessage::Request(Call::MethodCall(call))] => call.id.clone(),
+            messages => panic!("expected one request, got {:?}", messages),
+        }
+    }
+
+    fn failure(id: Id, code: i64) -> ServerMessage {
+        ServerMessage::Response(Output::Failure(jsonrpc_core::Failure {
+            jsonrpc: Some(Version::V2),
+            error: jsonrpc_core::Error::new(ErrorCode::ServerError(code)),
+            id,
+        }))
+    }
+
+    fn document_symbol_params() -> DocumentSymbolParams {
+        DocumentSymbolParams {
+            text_document: TextDocumentIdentifier {
+                uri: Uri::from_str("file:///x.rs").unwrap(),
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        }
+    }
+
+    #[test]
+    fn retry_content_modified_requests() {
+        let mut ctx = context_with_stub_server();
+        let meta = EditorMeta {
+            servers: vec![0],
+            ..EditorMeta::default()
+        };
+        let batch_id = ctx.call::<request::DocumentSymbolRequest, _>(
+            meta,
+            RequestParams::All(vec![document_symbol_params()]),
+            |_, _, _| panic!("the server never answered"),
+        );
+        let mut id = sent_request_id(&ctx);
+        for retries in 1..=MAX_REQUEST_RETRIES {
+            let _ = process_server_message(&mut ctx, 0, failure(id.clone(), CONTENT_MODIFIED));
+            let retry_id = sent_request_id(&ctx);
+            assert_ne!(retry_id, id);
+            assert_eq!(ctx.response_waitlist[&retry_id].retries, retries);
+            id = retry_id;
+        }
+        let _ = process_server_message(&mut ctx, 0, failure(id, CONTENT_MODIFIED));
+        assert!(sent_to_server(&ctx).is_empty());
+        assert!(ctx.response_waitlist.is_empty());
+        assert!(!ctx.batches.contains_key(&batch_id));
+    }
+
+    #[test]
+    fn resolve_code_lenses_only_once() {
+        let mut ctx = context_with_stub_server();
+        ctx.language_servers.get_mut(&0).unwrap().capabilities = Some(ServerCapabilities {
+            code_lens_provider: Some(lsp_types::CodeLensOptions {
+                resolve_provider: Some(true),
+            }),
+            ..ServerCapabilities::default()
+        });
+        let buffile = "/x.rs".to_string();
+        ctx.documents.insert(
+            buffile.clone(),
+            Document {
+                version: 1,
+                text: ropey::Rope::from_str("fn a() {}\nfn b() {}\n"),
+                content_provider: None,
+                history: Default::default(),
+                language_id: "rust".to_string(),
+                servers: vec![0],
+            },
+        );
+        let lens = |line| CodeLens {
+            range: Range::new(Position::new(line, 0), Position::new(line, 1)),
+            command: None,
+            data: None,
+        };
+        ctx.code_lenses
+            .insert(buffile.clone(), vec![(0, lens(0)), (0, lens(1))]);
+        let meta = EditorMeta {
+            buffile,
+            servers: vec![0],
+            ..EditorMeta::default()
+        };
+        let resolve = |ctx: &mut Context, first, last| {
+            let params = CodeLensRequestParams {
+                virtual_lines: false,
+                visible_lines: Some(VisibleLines { first, last }),
+            };
+            code_lens::resolve_visible_code_lenses(meta.clone(), params, ctx);
+            sent_to_server(ctx)
+                .into_iter()
+                .map(|message| match message {
+                    ServerMessage::Request(Call::MethodCall(call)) => call.id,
+                    message => panic!("expected a request, got {:?}", message),
+                })
+                .collect::<Vec<_>>()
+        };
+        let ids = resolve(&mut ctx, 1, 1);
+        assert_eq!(ids.len(), 1);
+        // Neither a lens that stays unresolved nor one that failed to resolve is sent again.
+        let _ = process_server_message(
+            &mut ctx,
+            0,
+            ServerMessage::Response(Output::Success(jsonrpc_core::Success {
+                jsonrpc: Some(Version::V2),
+                result: serde_json::to_value(lens(0)).unwrap(),
+                id: ids[0].clone(),
+            })),
+        );
+        assert!(resolve(&mut ctx, 1, 1).is_empty());
+        let ids = resolve(&mut ctx, 1, 2);
+        assert_eq!(ids.len(), 1);
+        let _ = process_server_message(&mut ctx, 0, failure(ids[0].clone(), -32603));
+        assert!(resolve(&mut ctx, 1, 2).is_empty());
+        // Until the lenses are replaced.
+        ctx.code_lenses_resolve_attempted.clear();
+        assert_eq!(resolve(&mut ctx, 1, 2).len(), 2);
+    }
+
+    #[test]
+    fn dont_retry_requests_about_a_position() {
+        let mut ctx = context_with_stub_server();
+        let meta = EditorMeta {
+            servers: vec![0],
+            ..EditorMeta::default()
+        };
+        ctx.call::<request::HoverRequest, _>(
+            meta,
+            RequestParams::All(vec![HoverParams {
+                text_document_position_params: TextDocumentPositionParams {
+                    text_document: document_symbol_params().text_document,
+                    position: Position::new(0, 0),
+                },
+                work_done_progress_params: Default::default(),
+            }]),
+            |_, _, _| panic!("the server never answered"),
+        );
+        let id = sent_request_id(&ctx);
+        let _ = process_server_message(&mut ctx, 0, failure(id, CONTENT_MODIFIED));
+        assert!(sent_to_server(&ctx).is_empty());
+        assert!(ctx.response_waitlist.is_empty());
+    }
+
+    #[test]
+    fn server_cancels_message_request() {
+        let mut ctx = context_with_stub_server();
+        let params = ShowMessageRequestParams {
+            typ: MessageType::INFO,
+            message: "hello".to_string(),
+            actions: None,
+        };
+        ctx.pending_message_requests
+            .push_back((Id::Num(1), 0, params.clone()));
+        ctx.displayed_message_requests.insert((0, Id::Num(2)));
+        let cancel = |ctx: &mut Context, id: NumberOrString| {
+            dispatch_server_notification(
+                0,
+                EditorMeta::default(),
+                notification::Cancel::METHOD,
+                Params::Map(
+                    serde_json::to_value(CancelParams { id })
+                        .unwrap()
+                        .as_object()
+                        .unwrap()
+                        .clone(),
+                ),
+                ctx,
+            );
+        };
+        let cancelled = |ctx: &Context| -> Vec<Id> {
+            sent_to_server(ctx)
+                .into_iter()
+                .map(|message| match message {
+                    ServerMessage::Response(Output::Failure(failure)) => {
+                        assert_eq!(
+                            failure.error.code,
+                            ErrorCode::ServerError(REQUEST_CANCELLED)
+                        );
+                        failure.id
+                    }
+                    message => panic!("unexpected message {:?}", message),
+                })
+                .collect()
+        };
+        // Queued requests are dropped, displayed ones are answered.
+        cancel(&mut ctx, NumberOrString::Number(1));
+        assert!(ctx.pending_message_requests.is_empty());
+        assert_eq!(cancelled(&ctx), [Id::Num(1)]);
+        cancel(&mut ctx, NumberOrString::Number(2));
+        assert!(ctx.displayed_message_requests.is_empty());
+        assert_eq!(cancelled(&ctx), [Id::Num(2)]);
+        // Unknown and invalid ids are ignored.
+        cancel(&mut ctx, NumberOrString::Number(3));
+        cancel(&mut ctx, NumberOrString::Number(-1));
+        assert!(cancelled(&ctx).is_empty());
+    }
+
+    #[test]
+    fn time_out_requests_and_ignore_late_responses() {
+        let mut ctx = context_with_stub_server();
+        let timeouts = &mut ctx.config.server.request_timeouts;
+        timeouts.insert("*".to_string(), 1000);
+        timeouts.insert(request::DocumentSymbolRequest::METHOD.to_string(), 5000);
+        assert_eq!(
+            ctx.request_timeout(request::DocumentSymbolRequest::METHOD),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            ctx.request_timeout(request::HoverRequest::METHOD),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(ctx.request_timeout(request::Initialize::METHOD), None);
+        assert_eq!(ctx.request_timeout(request::Shutdown::METHOD), None);
+
+        let meta = EditorMeta {
+            servers: vec![0],
+            ..EditorMeta::default()
+        };
+        let batch_id = ctx.call::<request::DocumentSymbolRequest, _>(
+            meta,
+            RequestParams::All(vec![document_symbol_params()]),
+            |_, _, _| panic!("the request timed out"),
+        );
+        let id = sent_request_id(&ctx);
+        assert!(time_out_requests(&mut ctx).is_continue());
+        assert!(ctx.response_waitlist.contains_key(&id));
+
+        let deadline = ctx.next_request_deadline().unwrap();
+        ctx.response_waitlist.get_mut(&id).unwrap().sent_at -= Duration::from_secs(5);
+        assert!(ctx.next_request_deadline() < Some(deadline));
+        assert!(time_out_requests(&mut ctx).is_continue());
+        assert!(ctx.response_waitlist.is_empty());
+        assert!(!ctx.batches.contains_key(&batch_id));
+        assert_eq!(ctx.next_request_deadline(), None);
+        assert!(matches!(
+            &sent_to_server(&ctx)[..],
+            [ServerMessage::Request(Call::Notification(notification))]
+                if notification.method == notification::Cancel::METHOD
+        ));
+        let histogram = ctx
+            .metrics
+            .histogram("fake", request::DocumentSymbolRequest::METHOD);
+        assert_eq!(histogram.timeouts, 1);
+
+        assert!(gave_up_on_request(&ctx, &id));
+        // Responses to other requests we sent are not mistaken for late ones.
+        ctx.call::<request::DocumentSymbolRequest, _>(
+            EditorMeta {
+                servers: vec![0],
+                ..EditorMeta::default()
+            },
+            RequestParams::All(vec![document_symbol_params()]),
+            |_, _, _| {},
+        );
+        let other_id = sent_request_id(&ctx);
+        ctx.response_waitlist.remove(&other_id);
+        assert!(!gave_up_on_request(&ctx, &other_id));
+        let late_response = ServerMessage::Response(Output::Success(jsonrpc_core::Success {
+            jsonrpc: Some(Version::V2),
+            result: serde_json::Value::Null,
+            id: id.clone(),
+        }));
+        assert!(process_server_message(&mut ctx, 0, late_response).is_continue());
+        assert!(sent_to_server(&ctx).is_empty());
+
+        // Only the most recent timed-out requests are remembered.
+        for n in 0..TIMED_OUT_REQUESTS_LENGTH {
+            let meta = EditorMeta {
+                servers: vec![0],
+                ..EditorMeta::default()
+            };
+            ctx.call::<request::DocumentSymbolRequest, _>(
+                meta,
+                RequestParams::All(vec![document_symbol_params()]),
+                |_, _, _| panic!("the request timed out"),
+            );
+            let newer_id = sent_request_id(&ctx);
+            ctx.response_waitlist.get_mut(&newer_id).unwrap().sent_at -= Duration::from_secs(5);
+            assert!(time_out_requests(&mut ctx).is_continue());
+            sent_to_server(&ctx);
+            assert!(gave_up_on_request(&ctx, &newer_id));
+            assert_eq!(
+                gave_up_on_request(&ctx, &id),
+                n + 1 < TIMED_OUT_REQUESTS_LENGTH
+            );
+        }
+    }
+
+    #[test]
+    fn replay_trace() {
+        let request = |text_line_count: &str, args: &[&str]| {
+            let tokens: Vec<&str> = [
+                "sess",
+                "client0",
+                text_line_count,
+                "false",
+                "false",
+                "/nonexistent/a.txt",
+                "1",
+                "text",
+                "plaintext",
+                "[fake]\nroot = \"/nonexistent\"\ncommand = \"fake-ls\"\n",
+                "[]",
+                "",
+                "map-end",
+            ]
+            .iter()
+            .chain(args)
+            .copied()
+            .collect();
+            serde_json::json!({"type": trace::EDITOR_REQUEST, "tokens": tokens, "timestamp": 0})
+        };
+        let message = |kind: &str, message: serde_json::Value| {
+            serde_json::json!({
+                "isLSPMessage": true,
+                "type": kind,
+                "message": message,
+                "server": "fake",
+                "timestamp": 0,
+            })
+        };
+        let mut did_open = request("1", &["textDocument/didOpen"]);
+        did_open["texts"] = serde_json::json!(["hello\n"]);
+        let entries = vec![
+            did_open,
+            message(
+                "send-request",
+                serde_json::json!({"jsonrpc": "2.0", "id": 7, "method": "initialize"}),
+            ),
+            message(
+                "receive-response",
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": 7,
+                    "result": {"capabilities": {"hoverProvider": true}},
+                }),
+            ),
+            request("0", &["textDocument/hover", "1.1,1.1", "8", ""]),
+            message(
+                "send-request",
+                serde_json::json!({"jsonrpc": "2.0", "id": 9, "method": "textDocument/hover"}),
+            ),
+            message(
+                "receive-response",
+                serde_json::json!({"jsonrpc": "2.0", "id": 9, "result": {"contents": "Hover text"}}),
+            ),
+        ]
+        .into_iter()
+        .map(|entry| serde_json::from_value(entry).unwrap())
+        .collect();
+        let mut config = Config::default();
+        config.server.shutdown_timeout = 0;
+        let mut out = vec![];
+        replay(config, entries, &mut out);
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("From editor: textDocument/didOpen"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with(r#"To server fake: {"jsonrpc":"2.0","method":"initialize","#));
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            [
+                r#"From server fake: {"jsonrpc":"2.0","result":{"capabilities":{"hoverProvider":true}},"id":0}"#,
+                r#"To server fake: {"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+                r#"To server fake: {"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"languageId":"plaintext","text":"hello\n","uri":"file:///nonexistent/a.txt","version":1}}}"#,
+                "From editor: textDocument/hover",
+                r#"To server fake: {"jsonrpc":"2.0","method":"textDocument/hover","params":{"position":{"character":0,"line":0},"textDocument":{"uri":"file:///nonexistent/a.txt"}},"id":1}"#,
+                r#"From server fake: {"jsonrpc":"2.0","result":{"contents":"Hover text"},"id":1}"#,
+                "To editor client client0: lsp-show-hover 1.1 %§Hover text§ %§§ %§§",
+            ]
+        );
+    }
+
+    #[test]
+    fn give_up_on_crashing_server() {
+        let mut ctx = context_with_stub_server();
+        let server_id = 0;
+        assert_eq!(running_servers(&ctx).0, [server_id]);
+        handle_server_crash(&mut ctx, server_id);
+        // The server is no longer configured, so every restart fails like another crash.
+        for _ in 0..MAX_SERVER_RESTARTS {
+            assert!(running_servers(&ctx).0.is_empty());
+            let server = ctx.language_servers.get_mut(&server_id).unwrap();
+            assert!(server.restart_at.is_some());
+            server.restart_at = Some(Instant::now());
+            restart_servers(&mut ctx);
+        }
+        let server = ctx.server(server_id);
+        assert_eq!(server.crash_count, MAX_SERVER_RESTARTS + 1);
+        assert!(server.restart_at.is_none());
+        assert!(running_servers(&ctx).0.is_empty());
+    }
+}
@@ -8,12 +8,121 @@ use itertools::EitherOrBoth;
 use itertools::Itertools;
 use jsonrpc_core::Params;
 use lsp_types::*;
+use ropey::Rope;
 use std::collections::HashMap;
 use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+/// Apply the server's configured filter, returning None if the diagnostic is dropped.
+fn filter_diagnostic(filter: &DiagnosticFilter, mut diagnostic: Diagnostic) -> Option<Diagnostic> {
+    // Like the rendering code, treat a missing severity as a warning.
+    let severity = diagnostic.severity.unwrap_or(DiagnosticSeverity::WARNING);
+    let rule = filter.rules.iter().find(|rule| {
+        rule.source
+            .as_ref()
+            .map_or(true, |source| diagnostic.source.as_ref() == Some(source))
+            && rule.code.as_ref().map_or(true, |code| {
+                diagnostic.code.as_ref().is_some_and(|c| match c {
+                    NumberOrString::Number(n) => n.to_string() == *code,
+                    NumberOrString::String(s) => s == code,
+                })
+            })
+            && rule
+                .message
+                .as_ref()
+                .map_or(true, |regex| regex.is_match(&diagnostic.message))
+    });
+    if let Some(rule) = rule {
+        // Rules without a severity drop the diagnostic.
+        let downgraded = DiagnosticSeverity::from(rule.severity?);
+        // Larger values are less severe.
+        if downgraded > severity {
+            diagnostic.severity = Some(downgraded);
+        }
+    }
+    if let Some(min_severity) = filter.min_severity {
+        let severity = diagnostic.severity.unwrap_or(DiagnosticSeverity::WARNING);
+        if severity > DiagnosticSeverity::from(min_severity) {
+            return None;
+        }
+    }
+    Some(diagnostic)
+}
+
+/// Move diagnostics that were computed for an older version to the current one, given the
+/// changes of each version since. Diagnostics on changed lines are dropped.
+fn shift_diagnostics<'a>(
+    diagnostics: Vec<Diagnostic>,
+    history: impl Iterator<Item = &'a [LineChange]> + Clone,
+) -> Vec<Diagnostic> {
+    diagnostics
+        .into_iter()
+        .filter_map(|mut d| {
+            let Range { start, end } = d.range;
+            // A range that ends at the start of a line doesn't touch that line.
+            let last_line = if end.character == 0 && end.line > start.line {
+                end.line - 1
+            } else {
+                end.line
+            };
+            let mut first_line = start.line as usize;
+            let line_count = last_line.saturating_sub(start.line) as usize;
+            for changes in history.clone() {
+                first_line = shift_lines(first_line, first_line + line_count, changes)?;
+            }
+            let delta = first_line as i64 - start.line as i64;
+            d.range.start.line = first_line as u32;
+            d.range.end.line = (end.line as i64 + delta) as u32;
+            Some(d)
+        })
+        .collect()
+}
+
+/// Where some lines of the old version start in the new one, unless any of them changed.
+fn shift_lines(first: usize, last: usize, changes: &[LineChange]) -> Option<usize> {
+    let mut new_first = first;
+    for change in changes {
+        if change.start > last {
+            break;
+        }
+        // Only lines inserted before the first line leave the lines intact.
+        if change.start > first || change.start + change.old_len > first {
+            return None;
+        }
+        new_first = new_first + change.new_len - change.old_len;
+    }
+    Some(new_first)
+}
 
 pub fn publish_diagnostics(server_id: ServerId, params: Params, ctx: &mut Context) {
     let params: PublishDiagnosticsParams = params.parse().expect("Failed to parse params");
     let buffile = uri_to_file_path(&params.uri).to_string_lossy().into_owned();
+    let server = ctx.server(server_id);
+    let mut params_diagnostics = params.diagnostics;
+    if let (Some(version), Some(document)) = (params.version, ctx.documents.get(&buffile)) {
+        if version < document.version {
+            let Some(start) = document.history.iter().position(|(v, _)| *v == version) else {
+                debug!(
+                    ctx.to_editor(),
+                    "Dropping diagnostics for outdated version {} of {}", version, buffile
+                );
+                return;
+            };
+            params_diagnostics = shift_diagnostics(
+                params_diagnostics,
+                document
+                    .history
+                    .range(start..)
+                    .map(|(_, changes)| changes.as_slice()),
+            );
+        }
+    }
+    let filter = &server.diagnostic_filter;
+    let params: Vec<_> = params_diagnostics
+        .into_iter()
+        .filter_map(|d| filter_diagnostic(filter, d))
+        .map(|d| (server_id, d))
+        .collect();
     let mut diagnostics: Vec<_> = ctx
         .diagnostics
         .remove(&buffile)
@@ -21,13 +130,9 @@ pub fn publish_diagnostics(server_id: ServerId, params: Params, ctx: &mut Contex
         .into_iter()
         .filter(|(id, _)| id != &server_id)
         .collect();
-    let params: Vec<_> = params
-        .diagnostics
-        .into_iter()
-        .map(|d| (server_id, d))
-        .collect();
     diagnostics.extend(params);
     ctx.diagnostics.insert(buffile.clone(), diagnostics);
+    schedule_diagnostics_buffer_refresh(ctx);
     let document = ctx.documents.get(&buffile);
     if document.is_none() {
         return;
@@ -242,7 +347,13 @@ pub fn gather_line_flags(ctx: &Context, buffile: &str) -> (String, u32, u32, u32
             EitherOrBoth::Right((line, lens_label)) => (line, lens_label),
             EitherOrBoth::Both((line, diagnostic_label), _) => (line, diagnostic_label),
         })
-        .map(|(line, label)| format!("'{}|{}'", line + 1, label))
+        .map(|(line, label)| {
+            let line = match ctx.documents.get(buffile) {
+                Some(document) => lsp_line_to_kakoune(line, &document.text),
+                None => line + 1,
+            };
+            format!("'{}|{}'", line, label)
+        })
         .join(" ");
 
     (
@@ -313,66 +424,586 @@ pub fn diagnostic_text(
     }
 }
 
-pub fn editor_diagnostics(meta: EditorMeta, params: PositionParams, ctx: &mut Context) {
+#[derive(Clone, Debug)]
+pub struct DiagnosticsParams {
+    pub position: KakounePosition,
+    pub errors_only: bool,
+}
+
+pub fn editor_diagnostics(meta: EditorMeta, params: DiagnosticsParams, ctx: &mut Context) {
+    let (content, goto_buffer_line) = diagnostics_buffer_content(&meta, &params, ctx);
+    let command = format!(
+        "lsp-show-diagnostics {} {} {} {}",
+        editor_quote(ctx.main_root(&meta)),
+        editor_quote(&content),
+        goto_buffer_line.unwrap_or(1),
+        editor_quote(&meta.buffile),
+    );
+    ctx.diagnostics_buffer = Some((meta.clone(), params));
+    ctx.exec(meta, command);
+}
+
+/// How long to collect diagnostics before updating the *diagnostics* buffer. Servers often
+/// publish diagnostics for many files at once, and each update renders all of them.
+const DIAGNOSTICS_BUFFER_REFRESH_DELAY: Duration = Duration::from_millis(200);
+
+fn schedule_diagnostics_buffer_refresh(ctx: &mut Context) {
+    if ctx.diagnostics_buffer.is_some() && ctx.diagnostics_buffer_refresh_at.is_none() {
+        ctx.diagnostics_buffer_refresh_at = Some(Instant::now() + DIAGNOSTICS_BUFFER_REFRESH_DELAY);
+    }
+}
+
+/// Update the *diagnostics* buffer, if it's open, after diagnostics have changed.
+pub fn refresh_diagnostics_buffer(ctx: &mut Context) {
+    ctx.diagnostics_buffer_refresh_at = None;
+    let Some((meta, params)) = ctx.diagnostics_buffer.take() else {
+        return;
+    };
+    let (content, _) = diagnostics_buffer_content(&meta, &params, ctx);
+    let command = format!("lsp-refresh-diagnostics {}", editor_quote(&content));
+    ctx.exec(meta.clone(), command);
+    ctx.diagnostics_buffer = Some((meta, params));
+}
+
+/// Called when the user closes the *diagnostics* buffer.
+pub fn diagnostics_buffer_closed(ctx: &mut Context) {
+    ctx.diagnostics_buffer = None;
+    ctx.diagnostics_buffer_refresh_at = None;
+}
+
+/// Renders diagnostics grouped by file, with errors first, under headers with their counts.
+/// Also returns the line of the last diagnostic before the cursor.
+fn diagnostics_buffer_content(
+    meta: &EditorMeta,
+    params: &DiagnosticsParams,
+    ctx: &Context,
+) -> (String, Option<usize>) {
+    let severity = |x: &Diagnostic| x.severity.unwrap_or(DiagnosticSeverity::WARNING);
+    let files: Vec<_> = ctx
+        .diagnostics
+        .iter()
+        .map(|(filename, diagnostics)| {
+            let diagnostics: Vec<_> = diagnostics
+                .iter()
+                .filter(|(_, x)| !params.errors_only || severity(x) == DiagnosticSeverity::ERROR)
+                .sorted_by_key(|(_, x)| (severity(x), x.range.start))
+                .collect();
+            (filename, diagnostics)
+        })
+        .filter(|(_, diagnostics)| !diagnostics.is_empty())
+        .sorted_by_key(|(filename, _)| *filename)
+        .collect();
+
+    let all = files.iter().flat_map(|(_, diagnostics)| diagnostics);
+    let mut content = format!(
+        "{} in {} {}\n",
+        severity_counts(all.map(|(_, x)| severity(x))),
+        files.len(),
+        if files.len() == 1 { "file" } else { "files" }
+    );
     let mut goto_buffer_line = None;
-    let mut line = 1;
-    let content = ctx
+    let mut line = 2;
+    for (filename, diagnostics) in &files {
+        let short_path = short_file_path(filename, ctx.main_root(meta));
+        writeln!(
+            content,
+            "\n{}: {}",
+            short_path,
+            severity_counts(diagnostics.iter().map(|(_, x)| severity(x)))
+        )
+        .unwrap();
+        line += 2;
+        for (server_id, x) in diagnostics {
+            let server = ctx.server(*server_id);
+            let Some(p) = get_kakoune_position(server, filename, &x.range.start, ctx) else {
+                warn!(
+                    ctx.to_editor(),
+                    "Cannot get position from file {}", filename
+                );
+                continue;
+            };
+            if *filename == &meta.buffile && (goto_buffer_line.is_none() || p <= params.position) {
+                goto_buffer_line = Some(line);
+            }
+            let entry = format!(
+                "{}:{}:{}: {}{}\n",
+                short_path,
+                p.line,
+                p.column,
+                diagnostic_text(
+                    ctx.to_editor(),
+                    x,
+                    (diagnostics.len() > 1).then_some(&server.name),
+                    false,
+                ),
+                format_related_information(x, server, diagnostics.len() > 1, meta, ctx)
+                    .unwrap_or_default()
+            );
+            line += entry.chars().filter(|&c| c == '\n').count();
+            content.push_str(&entry);
+        }
+    }
+    (content, goto_buffer_line)
+}
+
+/// Summarizes severities like "2 errors, 1 warning".
+fn severity_counts(severities: impl Iterator<Item = DiagnosticSeverity>) -> String {
+    let severities: Vec<_> = severities.collect();
+    let summary = [
+        (DiagnosticSeverity::ERROR, "error", "errors"),
+        (DiagnosticSeverity::WARNING, "warning", "warnings"),
+        (DiagnosticSeverity::INFORMATION, "info", "info"),
+        (DiagnosticSeverity::HINT, "hint", "hints"),
+    ]
+    .iter()
+    .filter_map(|(severity, singular, plural)| {
+        let n = severities.iter().filter(|s| *s == severity).count();
+        (n != 0).then(|| format!("{} {}", n, if n == 1 { singular } else { plural }))
+    })
+    .join(", ");
+    if summary.is_empty() {
+        "no diagnostics".to_string()
+    } else {
+        summary
+    }
+}
+
+/// The diagnostics that contain the cursor, or if there are none, those on the cursor line.
+fn diagnostics_at_cursor<'a>(
+    meta: &EditorMeta,
+    cursor: &KakounePosition,
+    ctx: &'a Context,
+) -> Vec<&'a (ServerId, Diagnostic)> {
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return vec![];
+    };
+    let diagnostics = ctx
         .diagnostics
+        .get(&meta.buffile)
+        .map(|xs| xs.as_slice())
+        .unwrap_or_default();
+    let lsp_cursor = |server_id: ServerId| {
+        let server = ctx.server(server_id);
+        kakoune_position_to_lsp(cursor, &document.text, server.offset_encoding)
+    };
+    let on_cursor_line = diagnostics
         .iter()
-        .flat_map(|(filename, diagnostics)| {
-            diagnostics
+        .filter(|(server_id, x)| {
+            let position = lsp_cursor(*server_id);
+            ranges_touch_same_line(
+                x.range,
+                Range {
+                    start: position,
+                    end: position,
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    let at_cursor = on_cursor_line
+        .iter()
+        .copied()
+        .filter(|(server_id, x)| {
+            let position = lsp_cursor(*server_id);
+            x.range.start <= position && position <= x.range.end
+        })
+        .collect::<Vec<_>>();
+    if at_cursor.is_empty() {
+        on_cursor_line
+    } else {
+        at_cursor
+    }
+}
+
+/// Open the documentation of the diagnostics at the cursor, as linked by their code description.
+pub fn diagnostic_open_docs(meta: EditorMeta, params: PositionParams, ctx: &mut Context) {
+    let candidates = diagnostics_at_cursor(&meta, &params.position, ctx);
+    let links: Vec<_> = candidates
+        .into_iter()
+        .filter_map(|(server_id, x)| {
+            let href = x.code_description.as_ref()?.href.as_str();
+            let server_name = (meta.servers.len() > 1).then_some(&ctx.server(*server_id).name);
+            Some((
+                diagnostic_text(ctx.to_editor(), x, server_name.map(|s| s.as_str()), false),
+                href.to_string(),
+            ))
+        })
+        .unique_by(|(_, href)| href.clone())
+        .collect();
+    let command = match links.as_slice() {
+        [] => {
+            ctx.show_error(meta, "no diagnostic with a documentation link at cursor");
+            return;
+        }
+        [(_, href)] => format!("lsp-open-url {}", editor_quote(href)),
+        _ => format!(
+            "lsp-menu {}",
+            links
                 .iter()
-                .map(|(server_id, x)| {
-                    let server_id = *server_id;
-                    let server = ctx.server(server_id);
-                    let p = match get_kakoune_position(server, filename, &x.range.start, ctx) {
-                        Some(position) => position,
-                        None => {
-                            line += 1;
-                            warn!(
-                                ctx.to_editor(),
-                                "Cannot get position from file {}", filename
-                            );
-                            return "".to_string();
-                        }
-                    };
-                    if filename == &meta.buffile
-                        && (goto_buffer_line.is_none() || p <= params.position)
-                    {
-                        goto_buffer_line = Some(line);
-                    }
-                    let mut entry = format!(
-                        "{}:{}:{}: {}{}",
-                        short_file_path(filename, ctx.main_root(&meta)),
-                        p.line,
-                        p.column,
-                        diagnostic_text(
-                            ctx.to_editor(),
-                            x,
-                            (diagnostics.len() > 1).then_some(&server.name),
-                            false,
-                        ),
-                        format_related_information(x, server, diagnostics.len() > 1, &meta, ctx)
-                            .unwrap_or_default()
-                    );
-                    if entry.contains('\n') {
-                        entry.push('\n');
-                    }
-                    line += 1 + entry.chars().filter(|&c| c == '\n').count();
-                    entry
-                })
-                .collect::<Vec<_>>()
+                .map(|(text, href)| format!(
+                    "{} {}",
+                    editor_quote(text),
+                    editor_quote(&format!("lsp-open-url {}", editor_quote(href)))
+                ))
+                .join(" ")
+        ),
+    };
+    ctx.exec(meta, command);
+}
+
+/// List the diagnostics at the cursor together with their related locations in a goto buffer.
+pub fn diagnostic_related(meta: EditorMeta, params: PositionParams, ctx: &mut Context) {
+    let candidates: Vec<_> = diagnostics_at_cursor(&meta, &params.position, ctx)
+        .into_iter()
+        .filter(|(_, x)| {
+            x.related_information
+                .as_ref()
+                .is_some_and(|infos| !infos.is_empty())
+        })
+        .collect();
+    if candidates.is_empty() {
+        ctx.show_error(meta, "no diagnostic with related information at cursor");
+        return;
+    }
+    let root = ctx.main_root(&meta);
+    let content = candidates
+        .iter()
+        .map(|(server_id, x)| {
+            let server = ctx.server(*server_id);
+            let p = get_kakoune_position_with_fallback(server, &meta.buffile, x.range.start, ctx);
+            let server_name = (meta.servers.len() > 1).then_some(server.name.as_str());
+            let mut entry = format!(
+                "{}:{}:{}: {}\n",
+                short_file_path(&meta.buffile, root),
+                p.line,
+                p.column,
+                diagnostic_text(ctx.to_editor(), x, server_name, false),
+            );
+            for info in x.related_information.iter().flatten() {
+                let filename = uri_to_file_path(&info.location.uri);
+                let filename = filename.to_string_lossy();
+                let p = get_kakoune_position_with_fallback(
+                    server,
+                    &filename,
+                    info.location.range.start,
+                    ctx,
+                );
+                writeln!(
+                    entry,
+                    "{}:{}:{}: {}",
+                    short_file_path(&filename, root),
+                    p.line,
+                    p.column,
+                    info.message.trim().replace('\n', "␊"),
+                )
+                .unwrap();
+            }
+            entry
         })
-        .join("\n");
+        .join("");
     let command = format!(
-        "lsp-show-goto-buffer *diagnostics* lsp-diagnostics {} {} {}",
-        editor_quote(ctx.main_root(&meta)),
+        "lsp-show-goto-choices {} {}",
+        editor_quote(root),
         editor_quote(&content),
-        goto_buffer_line.unwrap_or(1)
     );
     ctx.exec(meta, command);
 }
 
+#[derive(Clone, Copy, Debug)]
+enum DiagnosticsExportFormat {
+    Sarif,
+    JsonLines,
+    Gnu,
+}
+
+#[derive(Clone, Debug)]
+pub struct DiagnosticsExportParams {
+    pub format: String,
+    pub path: String,
+}
+
+/// A diagnostic position, with both byte and code point columns.
+struct ExportPosition {
+    line: u32,
+    column: u32,
+    char_column: u32,
+}
+
+/// A diagnostic with its positions resolved, ready to be formatted for export.
+struct ExportDiagnostic {
+    path: String,
+    start: ExportPosition,
+    end: ExportPosition,
+    severity: DiagnosticSeverity,
+    server: ServerName,
+    source: Option<String>,
+    code: Option<String>,
+    code_description: Option<String>,
+    message: String,
+    related: Vec<ExportRelated>,
+}
+
+struct ExportRelated {
+    path: String,
+    position: ExportPosition,
+    message: String,
+}
+
+/// Write all diagnostics of this session to a file.
+pub fn export_diagnostics(meta: EditorMeta, params: DiagnosticsExportParams, ctx: &mut Context) {
+    let format = match params.format.as_str() {
+        "sarif" => DiagnosticsExportFormat::Sarif,
+        "jsonl" => DiagnosticsExportFormat::JsonLines,
+        "gnu" => DiagnosticsExportFormat::Gnu,
+        format => {
+            ctx.show_error(
+                meta,
+                format!(
+                    "unknown diagnostics export format '{format}', expected sarif, jsonl or gnu"
+                ),
+            );
+            return;
+        }
+    };
+    let mut contents: HashMap<String, Option<Rope>> = HashMap::new();
+    let mut position = |server: &ServerSettings, filename: &str, position: Position| {
+        let text = contents
+            .entry(filename.to_string())
+            .or_insert_with(|| get_file_contents(filename, ctx));
+        match text {
+            Some(text) => {
+                let p = lsp_position_to_kakoune(&position, text, server.offset_encoding);
+                let char_column =
+                    kakoune_position_to_lsp(&p, text, OffsetEncoding::Utf32).character + 1;
+                ExportPosition {
+                    line: p.line,
+                    column: p.column,
+                    char_column,
+                }
+            }
+            None => ExportPosition {
+                line: position.line + 1,
+                column: position.character + 1,
+                char_column: position.character + 1,
+            },
+        }
+    };
+
+    let mut diagnostics = vec![];
+    for (filename, file_diagnostics) in ctx.diagnostics.iter().sorted_by_key(|(f, _)| *f) {
+        for (server_id, d) in file_diagnostics
+            .iter()
+            .sorted_by_key(|(server_id, d)| (d.range.start, &ctx.server(*server_id).name))
+        {
+            let server = ctx.server(*server_id);
+            let related = d
+                .related_information
+                .iter()
+                .flatten()
+                .map(|info| {
+                    let path = uri_to_file_path(&info.location.uri)
+                        .to_string_lossy()
+                        .into_owned();
+                    ExportRelated {
+                        position: position(server, &path, info.location.range.start),
+                        path,
+                        message: info.message.clone(),
+                    }
+                })
+                .collect();
+            diagnostics.push(ExportDiagnostic {
+                path: filename.clone(),
+                start: position(server, filename, d.range.start),
+                end: position(server, filename, d.range.end),
+                severity: d.severity.unwrap_or(DiagnosticSeverity::WARNING),
+                server: server.name.clone(),
+                source: d.source.clone(),
+                code: d.code.as_ref().map(|code| match code {
+                    NumberOrString::Number(code) => code.to_string(),
+                    NumberOrString::String(code) => code.clone(),
+                }),
+                code_description: d.code_description.as_ref().map(|c| c.href.to_string()),
+                message: d.message.clone(),
+                related,
+            });
+        }
+    }
+
+    let root = ctx.main_root(&meta);
+    let output = match format {
+        DiagnosticsExportFormat::Gnu => export_gnu(&diagnostics, root),
+        DiagnosticsExportFormat::JsonLines => export_json_lines(&diagnostics, root),
+        DiagnosticsExportFormat::Sarif => export_sarif(&diagnostics),
+    };
+    if let Err(err) = std::fs::write(&params.path, output) {
+        ctx.show_error(
+            meta,
+            format!("failed to export diagnostics to {}: {}", params.path, err),
+        );
+        return;
+    }
+    let command = format!(
+        "echo -- {}",
+        editor_quote(&format!(
+            "exported {} diagnostics to {}",
+            diagnostics.len(),
+            params.path
+        ))
+    );
+    ctx.exec(meta, command);
+}
+
+/// One `file:line:column: severity: message` line per diagnostic, as understood by
+/// compilation modes in editors, followed by `note` lines for related information.
+fn export_gnu(diagnostics: &[ExportDiagnostic], root: &str) -> String {
+    let mut output = String::new();
+    for d in diagnostics {
+        let severity = match d.severity {
+            DiagnosticSeverity::ERROR => "error",
+            DiagnosticSeverity::WARNING => "warning",
+            _ => "note",
+        };
+        let tags: String = [Some(&d.server), d.source.as_ref(), d.code.as_ref()]
+            .iter()
+            .flatten()
+            .map(|tag| format!("[{tag}]"))
+            .collect();
+        writeln!(
+            output,
+            "{}:{}:{}: {severity}: {} {tags}",
+            short_file_path(&d.path, root),
+            d.start.line,
+            d.start.column,
+            d.message.trim().replace('\n', "␊"),
+        )
+        .unwrap();
+        for related in &d.related {
+            writeln!(
+                output,
+                "{}:{}:{}: note: {}",
+                short_file_path(&related.path, root),
+                related.position.line,
+                related.position.column,
+                related.message.replace('\n', "␊"),
+            )
+            .unwrap();
+        }
+    }
+    output
+}
+
+/// One JSON object per line and diagnostic.
+fn export_json_lines(diagnostics: &[ExportDiagnostic], root: &str) -> String {
+    let mut output = String::new();
+    for d in diagnostics {
+        let severity = match d.severity {
+            DiagnosticSeverity::ERROR => "error",
+            DiagnosticSeverity::HINT => "hint",
+            DiagnosticSeverity::INFORMATION => "info",
+            _ => "warning",
+        };
+        let related: Vec<_> = d
+            .related
+            .iter()
+            .map(|related| {
+                serde_json::json!({
+                    "file": short_file_path(&related.path, root),
+                    "line": related.position.line,
+                    "column": related.position.column,
+                    "message": related.message,
+                })
+            })
+            .collect();
+        let record = serde_json::json!({
+            "file": short_file_path(&d.path, root),
+            "line": d.start.line,
+            "column": d.start.column,
+            "end_line": d.end.line,
+            "end_column": d.end.column,
+            "severity": severity,
+            "server": d.server,
+            "source": d.source,
+            "code": d.code,
+            "code_description": d.code_description,
+            "message": d.message,
+            "related": related,
+        });
+        writeln!(output, "{record}").unwrap();
+    }
+    output
+}
+
+/// A SARIF log with one run per server.
+fn export_sarif(diagnostics: &[ExportDiagnostic]) -> String {
+    let location = |path: &str, start: &ExportPosition, end: Option<&ExportPosition>| {
+        let mut region = serde_json::json!({
+            "startLine": start.line,
+            "startColumn": start.char_column,
+        });
+        if let Some(end) = end {
+            region["endLine"] = end.line.into();
+            region["endColumn"] = end.char_column.into();
+        }
+        let uri = file_path_to_uri(path);
+        serde_json::json!({
+            "artifactLocation": { "uri": uri.as_str() },
+            "region": region,
+        })
+    };
+    let runs: Vec<_> = diagnostics
+        .iter()
+        .map(|d| {
+            let related: Vec<_> = d
+                .related
+                .iter()
+                .enumerate()
+                .map(|(i, related)| {
+                    serde_json::json!({
+                        "id": i,
+                        "physicalLocation": location(&related.path, &related.position, None),
+                        "message": { "text": related.message },
+                    })
+                })
+                .collect();
+            let mut result = serde_json::json!({
+                "level": match d.severity {
+                    DiagnosticSeverity::ERROR => "error",
+                    DiagnosticSeverity::WARNING => "warning",
+                    _ => "note",
+                },
+                "message": { "text": d.message },
+                "locations": [{ "physicalLocation": location(&d.path, &d.start, Some(&d.end)) }],
+                "relatedLocations": related,
+                "properties": {
+                    "source": d.source,
+                    "codeDescription": d.code_description,
+                },
+            });
+            if let Some(code) = &d.code {
+                result["ruleId"] = code.as_str().into();
+            }
+            (&d.server, result)
+        })
+        .into_group_map()
+        .into_iter()
+        .sorted_by_key(|(server, _)| *server)
+        .map(|(server, results)| {
+            serde_json::json!({
+                "tool": { "driver": { "name": server } },
+                "columnKind": "unicodeCodePoints",
+                "results": results,
+            })
+        })
+        .collect();
+    let log = serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": runs,
+    });
+    let mut output = serde_json::to_string_pretty(&log).unwrap();
+    output.push('\n');
+    output
+}
+
 pub fn format_related_information(
     d: &Diagnostic,
     server: &ServerSettings,
@@ -412,3 +1043,295 @@ pub fn format_related_information(
                     .join("\n")
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_sync::line_changes;
+
+    fn diagnostic(start: (u32, u32), end: (u32, u32)) -> Diagnostic {
+        Diagnostic {
+            range: Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1)),
+            message: format!("{:?}", start),
+            ..Diagnostic::default()
+        }
+    }
+
+    fn filtered(filter: &str, diagnostic: Diagnostic) -> Option<Option<DiagnosticSeverity>> {
+        let filter: DiagnosticFilter = toml::from_str(filter).unwrap();
+        filter_diagnostic(&filter, diagnostic).map(|d| d.severity)
+    }
+
+    fn diagnostic_with(
+        severity: Option<DiagnosticSeverity>,
+        source: &str,
+        code: NumberOrString,
+        message: &str,
+    ) -> Diagnostic {
+        Diagnostic {
+            severity,
+            source: Some(source.to_string()),
+            code: Some(code),
+            message: message.to_string(),
+            ..Diagnostic::default()
+        }
+    }
+
+    #[test]
+    fn filter_diagnostic_by_min_severity() {
+        let filter = r#"min_severity = "warning""#;
+        let code = || NumberOrString::Number(1);
+        let error = Some(DiagnosticSeverity::ERROR);
+        let hint = Some(DiagnosticSeverity::HINT);
+        assert_eq!(
+            filtered(filter, diagnostic_with(error, "a", code(), "m")),
+            Some(error)
+        );
+        assert_eq!(
+            filtered(filter, diagnostic_with(hint, "a", code(), "m")),
+            None
+        );
+        // A missing severity counts as a warning.
+        assert_eq!(
+            filtered(filter, diagnostic_with(None, "a", code(), "m")),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn filter_diagnostic_by_first_matching_rule() {
+        let filter = r#"
+            min_severity = "info"
+            [[rules]]
+            source = "clippy"
+            code = "needless_return"
+            [[rules]]
+            code = "42"
+            severity = "hint"
+            [[rules]]
+            message = "^unused"
+            severity = "info"
+        "#;
+        let error = Some(DiagnosticSeverity::ERROR);
+        let hint = Some(DiagnosticSeverity::HINT);
+        let string = |code: &str| NumberOrString::String(code.to_string());
+        // Rules without a severity drop matching diagnostics.
+        assert_eq!(
+            filtered(
+                filter,
+                diagnostic_with(error, "clippy", string("needless_return"), "m")
+            ),
+            None
+        );
+        assert_eq!(
+            filtered(
+                filter,
+                diagnostic_with(error, "rustc", string("needless_return"), "m")
+            ),
+            Some(error)
+        );
+        // Numeric codes match their decimal string, and min_severity applies after the rules.
+        assert_eq!(
+            filtered(
+                filter,
+                diagnostic_with(error, "rustc", NumberOrString::Number(42), "m")
+            ),
+            None
+        );
+        assert_eq!(
+            filtered(
+                filter,
+                diagnostic_with(error, "rustc", string("x"), "unused variable")
+            ),
+            Some(Some(DiagnosticSeverity::INFORMATION))
+        );
+        // Rules never make a diagnostic more severe.
+        assert_eq!(
+            filtered(
+                filter,
+                diagnostic_with(hint, "rustc", string("x"), "unused variable")
+            ),
+            None
+        );
+        // Message regexes are searched, so anchors apply.
+        assert_eq!(
+            filtered(
+                filter,
+                diagnostic_with(error, "rustc", string("x"), "an unused variable")
+            ),
+            Some(error)
+        );
+        let filter = r#"
+            [[rules]]
+            message = "unused"
+            severity = "warning"
+        "#;
+        assert_eq!(
+            filtered(
+                filter,
+                diagnostic_with(hint, "rustc", string("x"), "unused variable")
+            ),
+            Some(hint)
+        );
+    }
+
+    fn export_position(line: u32, column: u32) -> ExportPosition {
+        ExportPosition {
+            line,
+            column,
+            char_column: column,
+        }
+    }
+
+    fn export_diagnostics() -> Vec<ExportDiagnostic> {
+        vec![
+            ExportDiagnostic {
+                path: "/project/src/main.rs".to_string(),
+                start: export_position(3, 5),
+                end: export_position(3, 9),
+                severity: DiagnosticSeverity::ERROR,
+                server: "rust-analyzer".to_string(),
+                source: Some("rustc".to_string()),
+                code: Some("E0308".to_string()),
+                code_description: None,
+                message: "mismatched types\nexpected u32".to_string(),
+                related: vec![ExportRelated {
+                    path: "/project/src/lib.rs".to_string(),
+                    position: export_position(1, 1),
+                    message: "defined here".to_string(),
+                }],
+            },
+            ExportDiagnostic {
+                path: "/project/src/main.rs".to_string(),
+                start: export_position(4, 1),
+                end: export_position(4, 2),
+                severity: DiagnosticSeverity::HINT,
+                server: "clippy".to_string(),
+                source: None,
+                code: None,
+                code_description: None,
+                message: "consider this".to_string(),
+                related: vec![],
+            },
+        ]
+    }
+
+    #[test]
+    fn export_diagnostics_gnu() {
+        assert_eq!(
+            export_gnu(&export_diagnostics(), "/project"),
+            "src/main.rs:3:5: error: mismatched types␊expected u32 [rust-analyzer][rustc][E0308]\n\
+             src/lib.rs:1:1: note: defined here\n\
+             src/main.rs:4:1: note: consider this [clippy]\n"
+        );
+    }
+
+    #[test]
+    fn export_diagnostics_json_lines() {
+        let output = export_json_lines(&export_diagnostics(), "/project");
+        let records: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            records[0],
+            serde_json::json!({
+                "file": "src/main.rs",
+                "line": 3,
+                "column": 5,
+                "end_line": 3,
+                "end_column": 9,
+                "severity": "error",
+                "server": "rust-analyzer",
+                "source": "rustc",
+                "code": "E0308",
+                "code_description": null,
+                "message": "mismatched types\nexpected u32",
+                "related": [
+                    { "file": "src/lib.rs", "line": 1, "column": 1, "message": "defined here" },
+                ],
+            })
+        );
+        assert_eq!(records[1]["severity"], "hint");
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn export_diagnostics_sarif() {
+        let log: serde_json::Value =
+            serde_json::from_str(&export_sarif(&export_diagnostics())).unwrap();
+        assert_eq!(log["version"], "2.1.0");
+        let runs = log["runs"].as_array().unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0]["tool"]["driver"]["name"], "clippy");
+        assert_eq!(runs[0]["results"][0]["level"], "note");
+        assert_eq!(runs[1]["tool"]["driver"]["name"], "rust-analyzer");
+        let result = &runs[1]["results"][0];
+        assert_eq!(result["level"], "error");
+        assert_eq!(result["ruleId"], "E0308");
+        assert_eq!(
+            result["locations"][0]["physicalLocation"],
+            serde_json::json!({
+                "artifactLocation": { "uri": "file:///project/src/main.rs" },
+                "region": { "startLine": 3, "startColumn": 5, "endLine": 3, "endColumn": 9 },
+            })
+        );
+        assert_eq!(
+            result["relatedLocations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "file:///project/src/lib.rs"
+        );
+    }
+
+    #[test]
+    fn shift_diagnostics_to_current_version() {
+        let versions = ["a\nb\nc\nd\n", "a\nnew\nb\nc\nd\n", "a\nnew\nb\nC\nd\n"]
+            .iter()
+            .map(|text| Rope::from_str(text))
+            .collect::<Vec<_>>();
+        let history: Vec<_> = versions
+            .windows(2)
+            .map(|texts| line_changes(&texts[0], &texts[1]))
+            .collect();
+        let diagnostics = vec![
+            diagnostic((0, 0), (0, 1)),
+            // Up to the end of the line.
+            diagnostic((1, 0), (1, u32::MAX)),
+            diagnostic((2, 0), (2, 1)),
+            // Ends at the start of the next line.
+            diagnostic((3, 0), (4, 0)),
+            diagnostic((1, 0), (3, 1)),
+        ];
+        assert_eq!(
+            shift_diagnostics(
+                diagnostics,
+                history.iter().map(|changes| changes.as_slice())
+            ),
+            [
+                diagnostic((0, 0), (0, 1)),
+                Diagnostic {
+                    message: "(1, 0)".to_string(),
+                    ..diagnostic((2, 0), (2, u32::MAX))
+                },
+                Diagnostic {
+                    message: "(3, 0)".to_string(),
+                    ..diagnostic((4, 0), (5, 0))
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn shift_diagnostics_drops_diagnostics_around_inserted_lines() {
+        let old_text = Rope::from_str("a\nb\nc\n");
+        let text = Rope::from_str("a\nb\nnew\nc\n");
+        let changes = line_changes(&old_text, &text);
+        let diagnostics = vec![diagnostic((1, 0), (2, 1)), diagnostic((2, 0), (2, 1))];
+        assert_eq!(
+            shift_diagnostics(diagnostics, std::iter::once(changes.as_slice())),
+            [Diagnostic {
+                message: "(2, 0)".to_string(),
+                ..diagnostic((3, 0), (3, 1))
+            }]
+        );
+    }
+}
@@ -34,7 +34,7 @@ pub fn call_hierarchy_prepare(meta: EditorMeta, params: CallHierarchyParams, ctx
         move |ctx: &mut Context, meta, results| {
             request_call_hierarchy(meta, ctx, params.incoming_or_outgoing, results);
         },
-    )
+    );
 }
 
 fn request_call_hierarchy(
@@ -205,6 +205,7 @@ fn format_call_hierarchy_calls<'a>(
                     let line = line
                         .strip_suffix("\r\n")
                         .or_else(|| line.strip_suffix('\n'))
+                        .or_else(|| line.strip_suffix('\r'))
                         .unwrap_or(&line);
                     format_location(
                         server_id,
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use super::code_action::execute_command_editor_command;
-use crate::capabilities::CAPABILITY_CODE_LENS;
+use crate::capabilities::{CAPABILITY_CODE_LENS, CAPABILITY_CODE_LENS_RESOLVE};
 use crate::context::*;
 use crate::diagnostics::gather_line_flags;
 use crate::position::*;
@@ -16,8 +16,24 @@ use itertools::Itertools;
 use lazy_static::lazy_static;
 use lsp_types::request::*;
 use lsp_types::*;
+use ropey::Rope;
 
-pub fn text_document_code_lens(meta: EditorMeta, ctx: &mut Context) {
+/// The lines currently shown in the client's window, from `%val{window_range}`.
+#[derive(Clone, Copy, Debug)]
+pub struct VisibleLines {
+    pub first: u32,
+    pub last: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct CodeLensRequestParams {
+    /// Render lenses on a virtual line above their target instead of inline.
+    pub virtual_lines: bool,
+    /// Unknown if the request doesn't come from a window.
+    pub visible_lines: Option<VisibleLines>,
+}
+
+pub fn text_document_code_lens(meta: EditorMeta, params: CodeLensRequestParams, ctx: &mut Context) {
     let eligible_servers: Vec<_> = ctx
         .servers(&meta)
         .filter(|(_, server)| server_has_capability(ctx.to_editor(), server, CAPABILITY_CODE_LENS))
@@ -44,12 +60,13 @@ pub fn text_document_code_lens(meta: EditorMeta, ctx: &mut Context) {
     ctx.call::<CodeLensRequest, _>(
         meta,
         RequestParams::Each(req_params),
-        |ctx: &mut Context, meta, results| editor_code_lens(meta, results, ctx),
+        move |ctx: &mut Context, meta, results| editor_code_lens(meta, params, results, ctx),
     );
 }
 
 fn editor_code_lens(
     meta: EditorMeta,
+    params: CodeLensRequestParams,
     results: Vec<(ServerId, Option<Vec<CodeLens>>)>,
     ctx: &mut Context,
 ) {
@@ -64,21 +81,58 @@ fn editor_code_lens(
     lenses.sort_by_key(|(_, lens)| lens.range.start);
 
     let buffile = &meta.buffile;
-    let document = match ctx.documents.get(buffile) {
-        Some(document) => document,
-        None => {
-            ctx.code_lenses.remove(buffile);
-            return;
-        }
+    if !ctx.documents.contains_key(buffile) {
+        ctx.code_lenses.remove(buffile);
+        return;
+    }
+    ctx.code_lenses.insert(buffile.clone(), lenses);
+    // Any resolve responses still in flight are for the old lenses.
+    *ctx.code_lens_generations
+        .entry(buffile.clone())
+        .or_default() += 1;
+    ctx.code_lenses_resolve_attempted.remove(buffile);
+
+    render_code_lenses(buffile, params.virtual_lines, ctx);
+    resolve_visible_code_lenses(meta, params, ctx);
+}
+
+fn render_code_lenses(buffile: &str, virtual_lines: bool, ctx: &Context) {
+    let Some(document) = ctx.documents.get(buffile) else {
+        return;
     };
+    let no_lenses = vec![];
+    let lenses = ctx.code_lenses.get(buffile).unwrap_or(&no_lenses);
     let version = document.version;
-    let inlay_code_lenses = lenses
+    let (inlay_code_lenses, code_lens_lines) = if virtual_lines {
+        (String::new(), code_lens_lines(lenses, &document.text))
+    } else {
+        (
+            inline_code_lenses(lenses, &document.text, ctx),
+            String::new(),
+        )
+    };
+
+    let line_flags = gather_line_flags(ctx, buffile).0;
+    let command = formatdoc!(
+         "evaluate-commands \"set-option buffer lsp_diagnostic_lines {version} {line_flags} '0|%opt[lsp_diagnostic_line_error_sign]'\"
+          set-option buffer lsp_inlay_code_lenses {version} {inlay_code_lenses}
+          set-option buffer lsp_code_lens_lines {version} {code_lens_lines}",
+    );
+    let command = format!(
+        "evaluate-commands -buffer {} %§{}§",
+        editor_quote(buffile),
+        command.replace('§', "§§")
+    );
+    ctx.exec(EditorMeta::default(), command);
+}
+
+fn inline_code_lenses(lenses: &[(ServerId, CodeLens)], text: &Rope, ctx: &Context) -> String {
+    lenses
         .iter()
         .map(|(server_id, lens)| {
             let server = ctx.server(*server_id);
             let label = lens.command.as_ref().map_or("", |v| &v.title);
-            let position =
-                lsp_position_to_kakoune(&lens.range.start, &document.text, server.offset_encoding);
+            let position = lsp_position_to_kakoune(&lens.range.start, text, server.offset_encoding);
             let line = position.line;
             lazy_static! {
                 static ref CODE_LENS_INDICATOR: &'static str =
@@ -91,35 +145,154 @@ fn editor_code_lens(
                 escape_tuple_element(&escape_kakoune_markup(label))
             ))
         })
-        .join(" ");
+        .join(" ")
+}
 
-    ctx.code_lenses.insert(meta.buffile.clone(), lenses);
+/// Joins the lenses of each line into a virtual line, inserted before the start of the
+/// target line. Lenses are numbered for `lsp-code-lens <index>`.
+fn code_lens_lines(lenses: &[(ServerId, CodeLens)], text: &Rope) -> String {
+    lenses
+        .iter()
+        .chunk_by(|(_, lens)| lens.range.start.line)
+        .into_iter()
+        .filter(|(line, _)| (*line as usize) < text.len_lines())
+        .map(|(line, lenses)| {
+            let label = lenses
+                .enumerate()
+                .map(|(i, (_, lens))| {
+                    let title = lens.command.as_ref().map_or("…", |v| &v.title);
+                    format!("[{}] {}", i + 1, escape_kakoune_markup(title))
+                })
+                .join(" │ ");
+            let target_line = text.line(line as usize);
+            let indent: String = target_line
+                .chars()
+                .take_while(|c| *c == ' ' || *c == '\t')
+                .collect();
+            let line = lsp_line_to_kakoune(line, text);
+            editor_quote(&format!(
+                "{line}.1+0|{indent}{{InlayCodeLens}}{}\n",
+                escape_tuple_element(&label),
+            ))
+        })
+        .join(" ")
+}
 
-    let line_flags = gather_line_flags(ctx, buffile).0;
-    let command = formatdoc!(
-         "evaluate-commands \"set-option buffer lsp_diagnostic_lines {version} {line_flags} '0|%opt[lsp_diagnostic_line_error_sign]'\"
-          set-option buffer lsp_inlay_code_lenses {version} {inlay_code_lenses}",
-    );
-    let command = format!(
-        "evaluate-commands -buffer {} %§{}§",
-        editor_quote(buffile),
-        command.replace('§', "§§")
+/// Send one batch of codeLens/resolve requests for the lenses on the visible lines that
+/// don't have a command yet. Once all responses are in, the lenses are rendered again.
+/// Each lens is only sent once, whether or not the server manages to resolve it.
+pub fn resolve_visible_code_lenses(
+    meta: EditorMeta,
+    params: CodeLensRequestParams,
+    ctx: &mut Context,
+) {
+    let Some(VisibleLines { first, last }) = params.visible_lines else {
+        return;
+    };
+    let (Some(document), Some(lenses)) = (
+        ctx.documents.get(&meta.buffile),
+        ctx.code_lenses.get(&meta.buffile),
+    ) else {
+        return;
+    };
+    let attempted = ctx.code_lenses_resolve_attempted.get(&meta.buffile);
+    let mut newly_attempted = vec![];
+    let mut req_params: HashMap<ServerId, Vec<CodeLens>> = HashMap::new();
+    for (idx, (server_id, lens)) in lenses.iter().enumerate() {
+        if lens.command.is_some() || attempted.is_some_and(|attempted| attempted.contains(&idx)) {
+            continue;
+        }
+        let server = ctx.server(*server_id);
+        if !server_has_capability(ctx.to_editor(), server, CAPABILITY_CODE_LENS_RESOLVE) {
+            continue;
+        }
+        let line =
+            lsp_position_to_kakoune(&lens.range.start, &document.text, server.offset_encoding).line;
+        if (first..=last).contains(&line) {
+            newly_attempted.push(idx);
+            req_params.entry(*server_id).or_default().push(lens.clone());
+        }
+    }
+    if req_params.is_empty() {
+        return;
+    }
+    ctx.code_lenses_resolve_attempted
+        .entry(meta.buffile.clone())
+        .or_default()
+        .extend(newly_attempted);
+    let generation = ctx.code_lens_generations.get(&meta.buffile).copied();
+    ctx.call::<CodeLensResolve, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx: &mut Context, meta, results| {
+            if ctx.code_lens_generations.get(&meta.buffile).copied() != generation {
+                // The lenses have been replaced in the meantime.
+                return;
+            }
+            let Some(lenses) = ctx.code_lenses.get_mut(&meta.buffile) else {
+                return;
+            };
+            for (server_id, resolved) in results {
+                if let Some((_, lens)) = lenses.iter_mut().find(|(id, lens)| {
+                    *id == server_id && lens.command.is_none() && lens.range == resolved.range
+                }) {
+                    *lens = resolved;
+                }
+            }
+            render_code_lenses(&meta.buffile, params.virtual_lines, ctx);
+        },
     );
-    ctx.exec(EditorMeta::default(), command);
 }
 
 #[derive(Clone, Debug)]
 pub struct CodeLensOptions {
     pub selection_desc: String,
+    /// 1-based index among the lenses on the cursor line, as shown in virtual lines.
+    pub index: Option<usize>,
 }
 
 pub fn resolve_and_perform_code_lens(meta: EditorMeta, params: CodeLensOptions, ctx: &mut Context) {
-    let (range, _cursor) = parse_kakoune_range(&params.selection_desc);
+    let (range, cursor) = parse_kakoune_range(&params.selection_desc);
     let document = match ctx.documents.get(&meta.buffile) {
         Some(document) => document,
         None => return,
     };
 
+    if let Some(index) = params.index {
+        let lens = ctx.code_lenses.get(&meta.buffile).and_then(|lenses| {
+            lenses
+                .iter()
+                .filter(|(_, lens)| {
+                    lsp_line_to_kakoune(lens.range.start.line, &document.text) == cursor.line
+                })
+                .nth(index.wrapping_sub(1))
+                .cloned()
+        });
+        let Some((server_id, lens)) = lens else {
+            ctx.show_error(
+                meta,
+                format!("no code lens with index {index} on this line"),
+            );
+            return;
+        };
+        if lens.command.is_some() {
+            execute_code_lens(meta, server_id, &lens, ctx);
+            return;
+        }
+        let mut req_params = HashMap::new();
+        req_params.insert(server_id, vec![lens]);
+        ctx.call::<CodeLensResolve, _>(
+            meta,
+            RequestParams::Each(req_params),
+            |ctx: &mut Context, meta, results| {
+                if let Some((server_id, lens)) = results.into_iter().next() {
+                    execute_code_lens(meta, server_id, &lens, ctx);
+                }
+            },
+        );
+        return;
+    }
+
     if let Some((server_id, lens)) = ctx
         .code_lenses
         .get(&meta.buffile)
@@ -173,6 +346,18 @@ pub fn resolve_and_perform_code_lens(meta: EditorMeta, params: CodeLensOptions,
     perform_code_lens(meta, &lenses, ctx);
 }
 
+fn execute_code_lens(meta: EditorMeta, server_id: ServerId, lens: &CodeLens, ctx: &mut Context) {
+    let Some(command) = &lens.command else {
+        ctx.show_error(meta, "code lens has no command");
+        return;
+    };
+    let server_name = &ctx.server(server_id).name;
+    ctx.exec(
+        meta,
+        execute_command_editor_command(server_name, command, false),
+    );
+}
+
 fn perform_code_lens(meta: EditorMeta, lenses: &[(ServerId, CodeLens)], ctx: &Context) {
     let command = format!(
         "lsp-perform-code-lens {}",
@@ -192,3 +377,27 @@ fn perform_code_lens(meta: EditorMeta, lenses: &[(ServerId, CodeLens)], ctx: &Co
     );
     ctx.exec(meta, command)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_lens_lines_are_inserted_before_the_target_line() {
+        let lens = |line, title: &str| CodeLens {
+            range: Range::new(Position::new(line, 4), Position::new(line, 8)),
+            command: Some(Command::new(title.to_string(), String::new(), None)),
+            data: None,
+        };
+        let lenses = [
+            (0, lens(1, "run")),
+            (0, lens(1, "debug")),
+            (0, lens(9, "gone")),
+        ];
+        let text = Rope::from_str("fn main() {\n    test();\n}\n");
+        assert_eq!(
+            code_lens_lines(&lenses, &text),
+            "'2.1+0|    {InlayCodeLens}[1] run │ [2] debug\n'"
+        );
+    }
+}
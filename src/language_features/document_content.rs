@@ -0,0 +1,170 @@
+//! Read-only buffers for documents that don't live on disk, such as class files inside jars
+//! (`jdt://`), remote modules (`deno:/`) or entries of zip archives (`zipfile://`).
+//!
+//! Their contents are fetched from the language server that handed out the URI. The buffer is
+//! named after the URI and registered in `ctx.documents`, so the same server keeps serving
+//! hover, goto and friends inside it.
+use crate::context::*;
+use crate::types::*;
+use crate::util::*;
+use itertools::Itertools;
+use lsp_types::request::Request;
+use lsp_types::*;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TextDocumentContentParams {
+    pub uri: Uri,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TextDocumentContentResult {
+    pub text: String,
+}
+
+/// Standard way to fetch virtual documents, as of LSP 3.18.
+pub struct TextDocumentContentRequest {}
+
+impl Request for TextDocumentContentRequest {
+    type Params = TextDocumentContentParams;
+    type Result = TextDocumentContentResult;
+    const METHOD: &'static str = "workspace/textDocumentContent";
+}
+
+/// eclipse.jdt.ls extension for `jdt://` URIs.
+pub struct ClassFileContentsRequest {}
+
+impl Request for ClassFileContentsRequest {
+    type Params = TextDocumentIdentifier;
+    type Result = Option<String>;
+    const METHOD: &'static str = "java/classFileContents";
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualTextDocumentParams {
+    pub text_document: TextDocumentIdentifier,
+}
+
+/// Deno extension for `deno:/` URIs.
+pub struct VirtualTextDocumentRequest {}
+
+impl Request for VirtualTextDocumentRequest {
+    type Params = VirtualTextDocumentParams;
+    type Result = Option<String>;
+    const METHOD: &'static str = "deno/virtualTextDocument";
+}
+
+pub type OpenedCallback = Box<dyn FnOnce(&mut Context, EditorMeta)>;
+
+/// Return the virtual documents among the given URIs that don't have a buffer yet.
+pub fn missing_virtual_documents<'a>(
+    uris: impl IntoIterator<Item = (ServerId, &'a Uri)>,
+    ctx: &Context,
+) -> Vec<(ServerId, Uri)> {
+    uris.into_iter()
+        .filter(|(_, uri)| is_virtual_uri(uri))
+        .filter(|(_, uri)| {
+            !ctx.documents
+                .contains_key(uri_to_file_path(uri).to_string_lossy().as_ref())
+        })
+        .map(|(server_id, uri)| (server_id, uri.clone()))
+        .unique_by(|(_, uri)| uri.clone())
+        .collect()
+}
+
+/// Create buffers for the given virtual documents, then run the callback. Documents are fetched
+/// one after the other; a document the server fails to provide stops the chain.
+pub fn open_virtual_documents(
+    meta: EditorMeta,
+    mut missing: Vec<(ServerId, Uri)>,
+    ctx: &mut Context,
+    callback: OpenedCallback,
+) {
+    let Some((server_id, uri)) = missing.pop() else {
+        callback(ctx, meta);
+        return;
+    };
+    let scheme = uri
+        .scheme()
+        .map(|scheme| scheme.as_str().to_ascii_lowercase());
+    let text_document = TextDocumentIdentifier { uri: uri.clone() };
+    let then = move |ctx: &mut Context, meta: EditorMeta, text: Option<String>| {
+        let Some(text) = text else {
+            ctx.show_error(
+                meta,
+                format!(
+                    "language server did not provide contents of {}",
+                    uri.as_str()
+                ),
+            );
+            return;
+        };
+        open_virtual_document(&meta, server_id, &uri, text, ctx);
+        open_virtual_documents(meta, missing, ctx, callback);
+    };
+    match scheme.as_deref() {
+        Some("jdt") => ctx.call::<ClassFileContentsRequest, _>(
+            meta,
+            RequestParams::Each(HashMap::from([(server_id, vec![text_document])])),
+            move |ctx, meta, results| {
+                let text = results.into_iter().next().and_then(|(_, text)| text);
+                then(ctx, meta, text)
+            },
+        ),
+        Some("deno") => ctx.call::<VirtualTextDocumentRequest, _>(
+            meta,
+            RequestParams::Each(HashMap::from([(
+                server_id,
+                vec![VirtualTextDocumentParams { text_document }],
+            )])),
+            move |ctx, meta, results| {
+                let text = results.into_iter().next().and_then(|(_, text)| text);
+                then(ctx, meta, text)
+            },
+        ),
+        _ => ctx.call::<TextDocumentContentRequest, _>(
+            meta,
+            RequestParams::Each(HashMap::from([(
+                server_id,
+                vec![TextDocumentContentParams {
+                    uri: text_document.uri,
+                }],
+            )])),
+            move |ctx, meta, results| {
+                let text = results.into_iter().next().map(|(_, result)| result.text);
+                then(ctx, meta, text)
+            },
+        ),
+    };
+}
+
+fn open_virtual_document(
+    meta: &EditorMeta,
+    server_id: ServerId,
+    uri: &Uri,
+    text: String,
+    ctx: &mut Context,
+) {
+    let buffile = uri_to_file_path(uri).to_string_lossy().into_owned();
+    let command = format!(
+        "lsp-virtual-document {} {} {}",
+        editor_quote(&buffile),
+        editor_quote(&meta.filetype),
+        editor_quote(&text),
+    );
+    ctx.documents.insert(
+        buffile,
+        Document {
+            version: 0,
+            text: Rope::from_str(&text),
+            content_provider: Some(server_id),
+            history: Default::default(),
+            language_id: meta.language_id.clone(),
+            servers: vec![server_id],
+        },
+    );
+    ctx.exec(meta.clone(), command);
+}
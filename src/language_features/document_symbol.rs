@@ -585,7 +585,7 @@ fn next_or_prev_symbol_details<T: Symbol<T> + 'static>(
 ) -> Option<(String, KakounePosition, String, SymbolKind)> {
     // Some language servers return symbol locations that are not sorted in ascending order.
     // Sort the results so we can find next and previous properly.
-    items.sort_by(|a, b| a.selection_range().start.cmp(&b.selection_range().start));
+    items.sort_by_key(|a| a.selection_range().start);
 
     // Setup an iterator dependending on whether we are searching forwards or backwards
     let it: Box<dyn Iterator<Item = &mut T>> = if params.search_next {
@@ -3,8 +3,14 @@ use crate::capabilities::{
     CAPABILITY_REFERENCES, CAPABILITY_TYPE_DEFINITION,
 };
 use crate::context::{Context, RequestParams};
+use crate::language_features::document_content::{
+    missing_virtual_documents, open_virtual_documents,
+};
 use crate::position::*;
-use crate::types::{BackwardKakouneRange, EditorMeta, KakouneRange, PositionParams, ServerId};
+use crate::types::{
+    BackwardKakouneRange, EditorMeta, ForwardKakouneRange, KakounePosition, KakouneRange,
+    PositionParams, ServerId,
+};
 use crate::util::{editor_quote, file_path_to_uri, short_file_path, uri_to_file_path};
 use indoc::formatdoc;
 use itertools::Itertools;
@@ -13,6 +19,7 @@ use lsp_types::request::{
     GotoTypeDefinitionResponse, References, Request,
 };
 use lsp_types::*;
+use ropey::Rope;
 
 pub fn goto(
     meta: EditorMeta,
@@ -21,7 +28,7 @@ pub fn goto(
 ) {
     // HACK: When using multiple language servers, we might get duplicates here. Filter them out.
     let mut seen: Vec<GotoDefinitionResponse> = vec![];
-    let locations: Vec<_> = results
+    let links: Vec<_> = results
         .into_iter()
         .filter_map(|(server_id, v)| match v {
             None => None,
@@ -34,34 +41,71 @@ pub fn goto(
             }
         })
         .flat_map(|(server_id, response)| match response {
-            GotoDefinitionResponse::Scalar(location) => vec![(server_id, location)],
-            GotoDefinitionResponse::Array(locations) => {
-                locations.into_iter().map(|v| (server_id, v)).collect()
-            }
-            GotoDefinitionResponse::Link(locations) => locations
+            GotoDefinitionResponse::Scalar(location) => vec![(server_id, location_link(location))],
+            GotoDefinitionResponse::Array(locations) => locations
                 .into_iter()
-                .map(
-                    |LocationLink {
-                         target_uri: uri,
-                         target_selection_range: range,
-                         ..
-                     }| (server_id, Location { uri, range }),
-                )
+                .map(|location| (server_id, location_link(location)))
                 .collect(),
+            GotoDefinitionResponse::Link(links) => {
+                links.into_iter().map(|link| (server_id, link)).collect()
+            }
         })
-        .collect();
+        .collect::<Vec<_>>();
 
-    match locations.len() {
+    highlight_origin(&meta, &links, ctx);
+
+    // Locations in virtual documents need a buffer before we can jump there.
+    let missing = missing_virtual_documents(
+        links
+            .iter()
+            .map(|(server_id, link)| (*server_id, &link.target_uri)),
+        ctx,
+    );
+    let opened = Box::new(move |ctx: &mut Context, meta| match links.len() {
         0 => {}
         1 => {
-            goto_location(meta, &locations[0], ctx);
+            goto_location(meta, &links[0], ctx);
         }
         _ => {
-            goto_locations(meta, &locations, ctx);
+            goto_locations(meta, &links, ctx);
         }
+    });
+    open_virtual_documents(meta, missing, ctx, opened);
+}
+
+/// A plain location is a link whose full range is the same as its selection range.
+fn location_link(Location { uri, range }: Location) -> LocationLink {
+    LocationLink {
+        origin_selection_range: None,
+        target_uri: uri,
+        target_range: range,
+        target_selection_range: range,
     }
 }
 
+/// Highlight the span that the server resolved, usually the word under the cursor, until the
+/// next key press in this buffer.
+fn highlight_origin(meta: &EditorMeta, links: &[(ServerId, LocationLink)], ctx: &mut Context) {
+    let Some((server_id, origin)) = links
+        .iter()
+        .find_map(|(server_id, link)| Some((*server_id, link.origin_selection_range?)))
+    else {
+        return;
+    };
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    let server = ctx.server(server_id);
+    let range = lsp_range_to_kakoune(&origin, &document.text, server.offset_encoding);
+    let command = formatdoc!(
+        "set-option buffer lsp_goto_origin {} {}|GotoOrigin
+         hook -once -always buffer NormalKey .* %{{ unset-option buffer lsp_goto_origin }}",
+        meta.version,
+        ForwardKakouneRange(range),
+    );
+    ctx.exec(meta.clone(), command);
+}
+
 pub fn edit_at_range(buffile: &str, range: KakouneRange, in_normal_mode: bool) -> String {
     let normal = if in_normal_mode { "" } else { "<a-semicolon>" };
     formatdoc!(
@@ -75,14 +119,21 @@ pub fn edit_at_range(buffile: &str, range: KakouneRange, in_normal_mode: bool) -
 
 fn goto_location(
     meta: EditorMeta,
-    (server_id, Location { uri, range }): &(ServerId, Location),
+    (
+        server_id,
+        LocationLink {
+            target_uri,
+            target_selection_range,
+            ..
+        },
+    ): &(ServerId, LocationLink),
     ctx: &mut Context,
 ) {
-    let path_str = uri_to_file_path(uri);
+    let path_str = uri_to_file_path(target_uri);
     let path_str = path_str.to_string_lossy();
     if let Some(contents) = get_file_contents(&path_str, ctx) {
         let server = ctx.server(*server_id);
-        let range = lsp_range_to_kakoune(range, &contents, server.offset_encoding);
+        let range = lsp_range_to_kakoune(target_selection_range, &contents, server.offset_encoding);
         let command = format!(
             "evaluate-commands -try-client %opt{{jumpclient}} -- {}",
             editor_quote(&edit_at_range(&path_str, range, true)),
@@ -91,32 +142,35 @@ fn goto_location(
     }
 }
 
-fn goto_locations(meta: EditorMeta, locations: &[(ServerId, Location)], ctx: &mut Context) {
-    let select_location = locations
+/// Don't let a link to a huge target (like a whole class) flood the goto buffer.
+const MAX_TARGET_LINES: u32 = 10;
+
+/// Render one line per link, pointing at its selection range. If the link's full range spans
+/// other lines, show them around it like grep's context lines.
+fn goto_locations(meta: EditorMeta, links: &[(ServerId, LocationLink)], ctx: &mut Context) {
+    let select_location = links
         .iter()
-        .chunk_by(|(_, Location { uri, .. })| uri_to_file_path(uri).to_string_lossy().into_owned())
+        .chunk_by(|(_, LocationLink { target_uri, .. })| {
+            uri_to_file_path(target_uri).to_string_lossy().into_owned()
+        })
         .into_iter()
-        .map(|(path_str, locations)| {
+        .map(|(path_str, links)| {
             let contents = match get_file_contents(&path_str, ctx) {
                 Some(contents) => contents,
                 None => return "".into(),
             };
-            locations
-                .map(|(server_id, Location { range, .. })| {
+            // Let's use the main server root path to dictate how
+            // file paths should look like in the goto buffer.
+            let short_path = short_file_path(&path_str, ctx.main_root(&meta));
+            links
+                .map(|(server_id, link)| {
                     let server = ctx.server(*server_id);
-                    let pos = lsp_range_to_kakoune(range, &contents, server.offset_encoding).start;
+                    let range = &link.target_selection_range;
                     if range.start.line as usize >= contents.len_lines() {
                         return "".into();
                     }
-                    // Let's use the main server root path to dictate how
-                    // file paths should look like in the goto buffer.
-                    format!(
-                        "{}:{}:{}:{}",
-                        short_file_path(&path_str, ctx.main_root(&meta)),
-                        pos.line,
-                        pos.column,
-                        contents.line(range.start.line as usize),
-                    )
+                    let pos = lsp_range_to_kakoune(range, &contents, server.offset_encoding).start;
+                    location_lines(short_path, link, pos, &contents)
                 })
                 .join("")
         })
@@ -129,6 +183,47 @@ fn goto_locations(meta: EditorMeta, locations: &[(ServerId, Location)], ctx: &mu
     ctx.exec(meta, command);
 }
 
+/// The goto buffer lines for one link. Context lines use `path:line-text`, which the jump
+/// commands never match because they expect a colon after the line number.
+fn location_lines(
+    short_path: &str,
+    link: &LocationLink,
+    pos: KakounePosition,
+    contents: &Rope,
+) -> String {
+    let selection_line = link.target_selection_range.start.line;
+    let last_line = (contents.len_lines() - 1) as u32;
+    let first = link
+        .target_range
+        .start
+        .line
+        .max(selection_line.saturating_sub(MAX_TARGET_LINES - 1))
+        .min(selection_line);
+    let last = link
+        .target_range
+        .end
+        .line
+        .min(first + MAX_TARGET_LINES - 1)
+        .min(last_line)
+        .max(selection_line);
+    (first..=last)
+        .map(|line| {
+            let text = contents.line(line as usize).to_string();
+            let text = text.trim_end_matches(['\r', '\n']);
+            if line == selection_line {
+                format!("{}:{}:{}:{}\n", short_path, pos.line, pos.column, text)
+            } else {
+                format!(
+                    "{}:{}-{}\n",
+                    short_path,
+                    lsp_line_to_kakoune(line, contents),
+                    text
+                )
+            }
+        })
+        .join("")
+}
+
 pub fn text_document_definition(
     declaration: bool,
     meta: EditorMeta,
@@ -321,3 +416,75 @@ pub fn text_document_references(meta: EditorMeta, params: PositionParams, ctx: &
         },
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use std::str::FromStr;
+
+    fn link(target: (u32, u32), selection_line: u32) -> LocationLink {
+        let line_range = |start, end| Range::new(Position::new(start, 0), Position::new(end, 0));
+        LocationLink {
+            origin_selection_range: None,
+            target_uri: Uri::from_str("file:///x.rs").unwrap(),
+            target_range: line_range(target.0, target.1),
+            target_selection_range: line_range(selection_line, selection_line),
+        }
+    }
+
+    fn lines(contents: &Rope, link: &LocationLink) -> Vec<String> {
+        let pos = KakounePosition {
+            line: link.target_selection_range.start.line + 1,
+            column: 1,
+        };
+        location_lines("x.rs", link, pos, contents)
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn location_lines_show_target_range_as_context() {
+        let contents = Rope::from_str("fn f(\n    a: u32,\n) {\n}\nfn g() {}\n");
+        assert_eq!(
+            lines(&contents, &link((0, 3), 0)),
+            vec![
+                "x.rs:1:1:fn f(",
+                "x.rs:2-    a: u32,",
+                "x.rs:3-) {",
+                "x.rs:4-}"
+            ]
+        );
+        assert_eq!(
+            lines(&contents, &link((0, 2), 1)),
+            vec!["x.rs:1-fn f(", "x.rs:2:1:    a: u32,", "x.rs:3-) {"]
+        );
+    }
+
+    #[test]
+    fn location_lines_clamp_context_around_selection() {
+        let text: String = (1..=30).map(|i| format!("line {}\n", i)).collect();
+        let contents = Rope::from_str(&text);
+        let shown = lines(&contents, &link((0, 29), 20));
+        assert_eq!(shown.len(), MAX_TARGET_LINES as usize);
+        assert_eq!(shown.first().unwrap(), "x.rs:12-line 12");
+        assert_eq!(shown.last().unwrap(), "x.rs:21:1:line 21");
+
+        let shown = lines(&contents, &link((25, 29), 25));
+        assert_eq!(shown.len(), 5);
+        assert_eq!(shown.last().unwrap(), "x.rs:30-line 30");
+    }
+
+    #[test]
+    fn location_lines_only_match_jump_regex_on_selection_line() {
+        // The regex lsp.kak uses to jump to goto buffer lines.
+        let jump = Regex::new(r"^\s*((?:\w[\w+.-]*:/)?[^:\n]+):(\d+)\b(?::(\d+)\b)?(?::([^\n]+))")
+            .unwrap();
+        let contents = Rope::from_str("fn f() {\n    x:3:y\n    let a = b:4:5:c;\n}\n");
+        let shown = lines(&contents, &link((0, 3), 0));
+        assert_eq!(shown.len(), 4);
+        assert!(jump.is_match(&shown[0]));
+        assert!(shown[1..].iter().all(|line| !jump.is_match(line)));
+    }
+}
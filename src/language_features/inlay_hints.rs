@@ -2,25 +2,36 @@ use std::{borrow::Cow, collections::HashMap};
 
 use itertools::Itertools;
 use lsp_types::{
-    request::InlayHintRequest, InlayHint, InlayHintLabel, InlayHintParams, Position, Range,
-    TextDocumentIdentifier, TextEdit,
+    request::{InlayHintRequest, InlayHintResolveRequest},
+    GotoDefinitionResponse, InlayHint, InlayHintKind, InlayHintLabel, InlayHintLabelPart,
+    InlayHintLabelPartTooltip, InlayHintParams, InlayHintTooltip, MarkupContent, MarkupKind,
+    Position, Range, TextDocumentIdentifier, TextEdit,
 };
 
 use crate::{
-    capabilities::{attempt_server_capability, CAPABILITY_INLAY_HINTS},
+    capabilities::{
+        attempt_server_capability, CAPABILITY_INLAY_HINTS, CAPABILITY_INLAY_HINTS_RESOLVE,
+    },
     context::{Context, RequestParams},
-    markup::escape_kakoune_markup,
+    editor_transport::ToEditorSender,
+    language_features::goto,
+    markup::{
+        escape_kakoune_markup, markdown_to_kakoune_markup, FACE_INFO_DEFAULT, FACE_INFO_HEADER,
+    },
     position::{
         kakoune_range_to_lsp, lsp_position_to_kakoune, parse_kakoune_range, ranges_overlap,
     },
     text_edit::apply_text_edits,
-    types::{EditorMeta, ServerId},
+    types::{EditorMeta, InlayHintKindFilter, KakounePosition, PositionParams, ServerId},
     util::{editor_quote, escape_tuple_element, file_path_to_uri},
 };
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 #[derive(Debug, PartialEq, Clone, Default)]
 pub struct InlayHintsOptions {
     pub buf_line_count: u32,
+    /// Truncate labels that are wider than this, unless it's 0.
+    pub max_length: usize,
 }
 
 pub fn inlay_hints(meta: EditorMeta, params: InlayHintsOptions, ctx: &mut Context) {
@@ -62,7 +73,7 @@ pub fn inlay_hints(meta: EditorMeta, params: InlayHintsOptions, ctx: &mut Contex
                     v
                 })
                 .collect();
-            inlay_hints_response(meta, results, ctx)
+            inlay_hints_response(meta, results, params.max_length, ctx)
         },
     );
 }
@@ -70,6 +81,7 @@ pub fn inlay_hints(meta: EditorMeta, params: InlayHintsOptions, ctx: &mut Contex
 pub fn inlay_hints_response(
     meta: EditorMeta,
     inlay_hints: Vec<(ServerId, InlayHint)>,
+    max_length: usize,
     ctx: &mut Context,
 ) {
     let document = match ctx.documents.get(&meta.buffile) {
@@ -77,6 +89,17 @@ pub fn inlay_hints_response(
         None => return,
     };
 
+    // Hidden hints are dropped entirely, so they can't be applied either.
+    let inlay_hints: Vec<_> = inlay_hints
+        .into_iter()
+        .filter(|(server_id, hint)| {
+            let server = ctx.server(*server_id);
+            ctx.server_config(&meta, &server.name)
+                .and_then(|cfg| cfg.inlay_hint_kinds.as_ref())
+                .map_or(true, |kinds| kinds.contains(&kind_filter(hint.kind)))
+        })
+        .collect();
+
     let ranges = inlay_hints
         .iter()
         .map(
@@ -85,6 +108,7 @@ pub fn inlay_hints_response(
                 InlayHint {
                     position,
                     label,
+                    kind,
                     padding_left,
                     padding_right,
                     ..
@@ -93,11 +117,12 @@ pub fn inlay_hints_response(
                 let server = ctx.server(*server_id);
                 let position =
                     lsp_position_to_kakoune(position, &document.text, server.offset_encoding);
-                let label = match label {
-                    InlayHintLabel::String(s) => Cow::Borrowed(s),
-                    InlayHintLabel::LabelParts(parts) => {
-                        Cow::Owned(parts.iter().map(|x| x.value.as_str()).collect())
-                    }
+                let label = label_text(label);
+                let label = truncate_label(&label, max_length);
+                let face = match kind_filter(*kind) {
+                    InlayHintKindFilter::Type => "InlayHintType",
+                    InlayHintKindFilter::Parameter => "InlayHintParameter",
+                    InlayHintKindFilter::Other => "InlayHint",
                 };
                 let padding_left = if padding_left.unwrap_or(false) {
                     " "
@@ -111,7 +136,7 @@ pub fn inlay_hints_response(
                 };
                 let label = escape_tuple_element(&escape_kakoune_markup(&label));
                 editor_quote(&format!(
-                    "{position}+0|{padding_left}{{InlayHint}}{label}{padding_right}",
+                    "{position}+0|{padding_left}{{{face}}}{label}{padding_right}",
                 ))
             },
         )
@@ -129,6 +154,237 @@ pub fn inlay_hints_response(
     ctx.exec(meta, command)
 }
 
+fn kind_filter(kind: Option<InlayHintKind>) -> InlayHintKindFilter {
+    match kind {
+        Some(InlayHintKind::TYPE) => InlayHintKindFilter::Type,
+        Some(InlayHintKind::PARAMETER) => InlayHintKindFilter::Parameter,
+        _ => InlayHintKindFilter::Other,
+    }
+}
+
+/// Shorten the label to the given display width, marking the cut with an ellipsis.
+fn truncate_label(label: &str, max_length: usize) -> Cow<'_, str> {
+    if max_length == 0 || UnicodeWidthStr::width(label) <= max_length {
+        return Cow::Borrowed(label);
+    }
+    let mut width = 0;
+    let mut end = 0;
+    for (idx, c) in label.char_indices() {
+        width += UnicodeWidthChar::width(c).unwrap_or(0);
+        if width > max_length - 1 {
+            break;
+        }
+        end = idx + c.len_utf8();
+    }
+    Cow::Owned(format!("{}…", &label[..end]))
+}
+
+fn label_text(label: &InlayHintLabel) -> Cow<'_, str> {
+    match label {
+        InlayHintLabel::String(s) => Cow::Borrowed(s),
+        InlayHintLabel::LabelParts(parts) => {
+            Cow::Owned(parts.iter().map(|x| x.value.as_str()).collect())
+        }
+    }
+}
+
+/// Show the tooltips of the inlay hint closest to the cursor on the same line, including
+/// the tooltips of its label parts.
+pub fn inlay_hint_hover(meta: EditorMeta, params: PositionParams, ctx: &mut Context) {
+    let has_tooltip = |hint: &InlayHint| {
+        hint.tooltip.is_some()
+            || matches!(&hint.label, InlayHintLabel::LabelParts(parts)
+                if parts.iter().any(|part| part.tooltip.is_some()))
+    };
+    with_nearest_inlay_hint(
+        meta,
+        params.position,
+        has_tooltip,
+        ctx,
+        move |ctx, meta, server_id, hint| {
+            let server = ctx.server(server_id);
+            let document = &ctx.documents[&meta.buffile];
+            let anchor =
+                lsp_position_to_kakoune(&hint.position, &document.text, server.offset_encoding);
+            let contents = inlay_hint_tooltip(ctx.to_editor(), &hint);
+            if contents.is_empty() {
+                ctx.show_error(meta, "inlay hint has no tooltip");
+                return;
+            }
+            let command = format!(
+                "lsp-show-hover {} %§{}§ '' ''",
+                anchor,
+                contents.replace('§', "§§"),
+            );
+            ctx.exec(meta, command);
+        },
+    );
+}
+
+/// Jump to the location attached to the label part of the inlay hint closest to the cursor
+/// on the same line. In a hint like `HashMap<Foo, Bar>`, pick the part whose column within
+/// the label is closest to the cursor's column relative to the hint.
+pub fn inlay_hint_goto(meta: EditorMeta, params: PositionParams, ctx: &mut Context) {
+    let has_location = |hint: &InlayHint| {
+        matches!(&hint.label, InlayHintLabel::LabelParts(parts)
+            if parts.iter().any(|part| part.location.is_some()))
+    };
+    let cursor = params.position;
+    with_nearest_inlay_hint(
+        meta,
+        cursor,
+        has_location,
+        ctx,
+        move |ctx, meta, server_id, hint| {
+            let server = ctx.server(server_id);
+            let document = &ctx.documents[&meta.buffile];
+            let anchor =
+                lsp_position_to_kakoune(&hint.position, &document.text, server.offset_encoding);
+            let offset = i64::from(cursor.column)
+                - i64::from(anchor.column)
+                - i64::from(hint.padding_left.unwrap_or(false));
+            let location = match hint.label {
+                InlayHintLabel::String(_) => None,
+                InlayHintLabel::LabelParts(mut parts) => nearest_label_part(&parts, offset)
+                    .and_then(|idx| parts.swap_remove(idx).location),
+            };
+            let Some(location) = location else {
+                ctx.show_error(meta, "inlay hint has no location");
+                return;
+            };
+            goto::goto(
+                meta,
+                vec![(server_id, Some(GotoDefinitionResponse::Scalar(location)))],
+                ctx,
+            );
+        },
+    );
+}
+
+/// Find the label part with a location that is closest to the given byte offset within the
+/// label.
+fn nearest_label_part(parts: &[InlayHintLabelPart], offset: i64) -> Option<usize> {
+    let mut start = 0;
+    parts
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, part)| {
+            let part_start = start;
+            start += part.value.len() as i64;
+            let last = (start - 1).max(part_start);
+            part.location.as_ref()?;
+            let distance = if offset < part_start {
+                part_start - offset
+            } else {
+                (offset - last).max(0)
+            };
+            Some((idx, distance))
+        })
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(idx, _)| idx)
+}
+
+/// Run the callback on the inlay hint closest to the cursor on the same line.
+/// If `is_resolved` says that the hint lacks the properties we need, ask the server to fill
+/// them in first, and remember the result.
+fn with_nearest_inlay_hint(
+    meta: EditorMeta,
+    cursor: KakounePosition,
+    is_resolved: impl Fn(&InlayHint) -> bool,
+    ctx: &mut Context,
+    callback: impl FnOnce(&mut Context, EditorMeta, ServerId, InlayHint) + 'static,
+) {
+    let nearest = ctx
+        .documents
+        .get(&meta.buffile)
+        .zip(ctx.inlay_hints.get(&meta.buffile))
+        .and_then(|(document, hints)| {
+            hints
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, (server_id, hint))| {
+                    let server = ctx.server(*server_id);
+                    let pos = lsp_position_to_kakoune(
+                        &hint.position,
+                        &document.text,
+                        server.offset_encoding,
+                    );
+                    (pos.line == cursor.line).then_some((idx, pos))
+                })
+                .min_by_key(|(_, pos)| pos.column.abs_diff(cursor.column))
+        });
+    let Some((idx, _)) = nearest else {
+        ctx.show_error(meta, "no inlay hint on this line");
+        return;
+    };
+    let (server_id, hint) = ctx.inlay_hints[&meta.buffile][idx].clone();
+    let server = ctx.server(server_id);
+    if is_resolved(&hint)
+        || !attempt_server_capability(
+            ctx,
+            (server_id, server),
+            &meta,
+            CAPABILITY_INLAY_HINTS_RESOLVE,
+        )
+    {
+        callback(ctx, meta, server_id, hint);
+        return;
+    }
+    let position = hint.position;
+    let label = label_text(&hint.label).into_owned();
+    ctx.call::<InlayHintResolveRequest, _>(
+        meta,
+        RequestParams::Each(HashMap::from([(server_id, vec![hint])])),
+        move |ctx, meta, results| {
+            let Some((_, resolved)) = results.into_iter().next() else {
+                return;
+            };
+            // The hints may have been refreshed in the meantime.
+            if let Some(entry) = ctx
+                .inlay_hints
+                .get_mut(&meta.buffile)
+                .and_then(|hints| hints.get_mut(idx))
+                .filter(|(id, hint)| {
+                    *id == server_id
+                        && hint.position == position
+                        && label_text(&hint.label) == label
+                })
+            {
+                entry.1 = resolved.clone();
+            }
+            callback(ctx, meta, server_id, resolved);
+        },
+    );
+}
+
+fn inlay_hint_tooltip(to_editor: &ToEditorSender, hint: &InlayHint) -> String {
+    let render = |markup: &MarkupContent| match markup.kind {
+        MarkupKind::Markdown => markdown_to_kakoune_markup(to_editor, &markup.value),
+        MarkupKind::PlainText => escape_kakoune_markup(&markup.value),
+    };
+    let mut sections = vec![];
+    match &hint.tooltip {
+        Some(InlayHintTooltip::String(tooltip)) => sections.push(escape_kakoune_markup(tooltip)),
+        Some(InlayHintTooltip::MarkupContent(tooltip)) => sections.push(render(tooltip)),
+        None => {}
+    }
+    if let InlayHintLabel::LabelParts(parts) = &hint.label {
+        for part in parts {
+            let tooltip = match &part.tooltip {
+                Some(InlayHintLabelPartTooltip::String(tooltip)) => escape_kakoune_markup(tooltip),
+                Some(InlayHintLabelPartTooltip::MarkupContent(tooltip)) => render(tooltip),
+                None => continue,
+            };
+            sections.push(format!(
+                "{{{FACE_INFO_HEADER}}}{}{{{FACE_INFO_DEFAULT}}}\n{}",
+                escape_kakoune_markup(part.value.trim()),
+                tooltip
+            ));
+        }
+    }
+    sections.join("\n---\n")
+}
+
 #[derive(Debug)]
 pub enum InlayHintApplyKind {
     /// Select the closest hint on the same line as the cursors
@@ -245,3 +501,131 @@ pub fn inlay_hint_apply(meta: EditorMeta, params: InlayHintApplyParams, ctx: &mu
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::{context_with_stub_server, Document};
+    use lsp_types::{Location, Uri};
+    use std::str::FromStr;
+
+    fn part(value: &str, has_location: bool) -> InlayHintLabelPart {
+        InlayHintLabelPart {
+            value: value.to_string(),
+            location: has_location.then(|| Location {
+                uri: Uri::from_str("file:///x.rs").unwrap(),
+                range: Range::default(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn nearest_label_part_by_column() {
+        // HashMap<Foo, Bar>
+        let parts = vec![
+            part("HashMap", true),
+            part("<", false),
+            part("Foo", true),
+            part(", ", false),
+            part("Bar", true),
+            part(">", false),
+        ];
+        assert_eq!(nearest_label_part(&parts, -3), Some(0));
+        assert_eq!(nearest_label_part(&parts, 0), Some(0));
+        assert_eq!(nearest_label_part(&parts, 6), Some(0));
+        assert_eq!(nearest_label_part(&parts, 9), Some(2));
+        assert_eq!(nearest_label_part(&parts, 13), Some(4));
+        assert_eq!(nearest_label_part(&parts, 40), Some(4));
+        assert_eq!(nearest_label_part(&parts[1..2], 0), None);
+    }
+
+    #[test]
+    fn truncate_label_to_display_width() {
+        assert_eq!(truncate_label("String", 0), "String");
+        assert_eq!(truncate_label("String", 6), "String");
+        assert_eq!(truncate_label("String", 4), "Str…");
+        assert_eq!(truncate_label("String", 1), "…");
+        assert_eq!(truncate_label("S", 1), "S");
+        // Wide characters take two columns and are never cut in half.
+        assert_eq!(truncate_label("日本語", 6), "日本語");
+        assert_eq!(truncate_label("日本語", 5), "日本…");
+        assert_eq!(truncate_label("日本語", 4), "日…");
+        assert_eq!(truncate_label("a日本", 3), "a…");
+        assert_eq!(truncate_label("日本語", 1), "…");
+        // Combining and zero-width characters take no columns and stay with what precedes them.
+        assert_eq!(truncate_label("e\u{301}tude", 5), "e\u{301}tude");
+        assert_eq!(truncate_label("e\u{301}tude", 3), "e\u{301}t…");
+        assert_eq!(truncate_label("ab\u{200b}cd", 3), "ab\u{200b}…");
+    }
+
+    #[test]
+    fn filter_inlay_hints_by_kind() {
+        let mut ctx = context_with_stub_server();
+        let buffile = "/x.rs".to_string();
+        ctx.documents.insert(
+            buffile.clone(),
+            Document {
+                version: 1,
+                text: ropey::Rope::from_str("let x = f(1);\n"),
+                content_provider: None,
+                history: Default::default(),
+                language_id: "rust".to_string(),
+                servers: vec![0],
+            },
+        );
+        let hint = |kind| InlayHint {
+            position: Position::new(0, 5),
+            label: InlayHintLabel::String("hint".to_string()),
+            kind,
+            text_edits: None,
+            tooltip: None,
+            padding_left: None,
+            padding_right: None,
+            data: None,
+        };
+        let hints = vec![
+            (0, hint(Some(InlayHintKind::TYPE))),
+            (0, hint(Some(InlayHintKind::PARAMETER))),
+            (0, hint(None)),
+        ];
+        let shown_kinds = |ctx: &Context| -> Vec<_> {
+            ctx.inlay_hints[&buffile]
+                .iter()
+                .map(|(_, hint)| hint.kind)
+                .collect()
+        };
+        let mut meta = EditorMeta {
+            buffile: buffile.clone(),
+            servers: vec![0],
+            ..EditorMeta::default()
+        };
+
+        // Without a configured filter, all kinds are shown.
+        inlay_hints_response(meta.clone(), hints.clone(), 0, &mut ctx);
+        assert_eq!(
+            shown_kinds(&ctx),
+            vec![
+                Some(InlayHintKind::TYPE),
+                Some(InlayHintKind::PARAMETER),
+                None
+            ]
+        );
+
+        let config = r#"
+            command = "fake-ls"
+            inlay_hint_kinds = ["type", "other"]
+        "#;
+        meta.language_server
+            .insert("fake".to_string(), toml::from_str(config).unwrap());
+        inlay_hints_response(meta.clone(), hints.clone(), 0, &mut ctx);
+        assert_eq!(shown_kinds(&ctx), vec![Some(InlayHintKind::TYPE), None]);
+
+        // The filter belongs to its server, so it leaves other servers' hints alone.
+        meta.language_server
+            .insert("other".to_string(), meta.language_server["fake"].clone());
+        meta.language_server.remove("fake");
+        inlay_hints_response(meta, hints, 0, &mut ctx);
+        assert_eq!(shown_kinds(&ctx).len(), 3);
+    }
+}
@@ -1,141 +1,613 @@
 use crate::editor_transport::ToEditorSender;
 use crate::thread_worker::Worker;
+use crate::trace;
 use crate::types::*;
-use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TryRecvError};
 use itertools::Itertools;
 use jsonrpc_core::{self, Call, Output};
 use std::collections::HashMap;
 use std::io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
-use std::process::{Command, Stdio};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct LanguageServerTransport {
     // The field order is important as it defines the order of drop.
-    // We want to exit a writer loop first (after sending exit notification),
+    // We want to stop connecting and exit a writer loop first (after sending exit notification),
     // then close all pipes and wait until child process is finished.
     // That helps to ensure that reader loop is not stuck trying to read from the language server.
+    _abort_connect: Sender<Void>,
     pub to_lang_server: Worker<ToEditorSender, ServerMessage, Void>,
     pub from_lang_server: Worker<ToEditorSender, Void, ServerMessage>,
-    _errors: Worker<ToEditorSender, Void, Void>,
+    _output_loggers: Vec<Worker<ToEditorSender, Void, u16>>,
+    pid: Option<u32>,
+    /// Why we could not connect to the server's socket.
+    connect_error: Arc<Mutex<Option<String>>>,
+    /// How the server exited after it stopped talking to us, if we spawned it.
+    exit_status: Arc<Mutex<Option<ExitStatus>>>,
+    /// For a stub server, the messages sent to it.
+    pub stub_receiver: Option<Receiver<ServerMessage>>,
 }
 
+impl LanguageServerTransport {
+    /// The process ID of the server, if we spawned it.
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Why we could not connect to a server that talks over a socket.
+    pub fn connect_error(&self) -> Option<String> {
+        self.connect_error.lock().unwrap().clone()
+    }
+
+    /// The exit status of a server that stopped talking to us, if we spawned it and it
+    /// exited shortly after. Known by the time `from_lang_server` is disconnected.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        *self.exit_status.lock().unwrap()
+    }
+}
+
+/// How long to wait for a server to print its port and accept connections.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before trying again to connect to a server we just spawned.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait for a server that closed its output to exit.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for a server to exit after SIGTERM, before sending SIGKILL.
+pub const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where to reach a language server that doesn't talk over stdio.
+#[derive(Debug, PartialEq, Eq)]
+enum SocketAddress {
+    /// Without a port, connect to the port the spawned server prints to stdout.
+    Tcp {
+        host: String,
+        port: Option<u16>,
+    },
+    Unix(PathBuf),
+}
+
+fn parse_address(address: &str) -> Result<SocketAddress, String> {
+    if let Some(rest) = address.strip_prefix("tcp://") {
+        let (host, port) = match rest
+            .rsplit_once(':')
+            .filter(|(_, port)| !port.contains(']'))
+        {
+            Some((host, port)) => (
+                host,
+                Some(
+                    port.parse::<u16>()
+                        .map_err(|_| format!("invalid port in address '{address}'"))?,
+                ),
+            ),
+            None => (rest, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("missing host in address '{address}'"));
+        }
+        return Ok(SocketAddress::Tcp {
+            host: host.to_string(),
+            port,
+        });
+    }
+    if let Some(path) = address.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err(format!("missing path in address '{address}'"));
+        }
+        return Ok(SocketAddress::Unix(PathBuf::from(path)));
+    }
+    Err(format!(
+        "unsupported address '{address}', expected tcp://host:port or unix:/path"
+    ))
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    fn connect(
+        address: &SocketAddress,
+        port: Option<u16>,
+        timeout: Duration,
+    ) -> io::Result<Socket> {
+        match address {
+            SocketAddress::Tcp { host, .. } => {
+                let port = port.expect("port of TCP address");
+                let mut result = Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("no address for host {host}"),
+                ));
+                for address in (host.as_str(), port).to_socket_addrs()? {
+                    result = TcpStream::connect_timeout(&address, timeout);
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                result.map(Socket::Tcp)
+            }
+            SocketAddress::Unix(path) => connect_unix(path, timeout).map(Socket::Unix),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Socket> {
+        match self {
+            Socket::Tcp(stream) => stream.try_clone().map(Socket::Tcp),
+            Socket::Unix(stream) => stream.try_clone().map(Socket::Unix),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Socket::Unix(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            Socket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            Socket::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            Socket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// How to talk to the server, before the writer thread connects to its socket.
+enum Connection {
+    Stdio(Box<dyn Write + Send>),
+    Socket(SocketAddress, Option<Receiver<u16>>),
+}
+
+/// Start a language server.
+///
+/// Without an address, spawn `cmd` and talk to it over stdio.
+/// With an address, connect to it, after spawning `cmd` if given. Connecting happens in the
+/// background, messages to the server wait until it is done.
+/// When the transport is dropped, a spawned server gets `shutdown_timeout` to exit
+/// before it is terminated.
 pub fn start(
     to_editor: &ToEditorSender,
     server_name: ServerName,
-    cmd: &str,
+    cmd: Option<&str>,
     args: &[String],
     envs: &HashMap<String, String>,
+    address: Option<&str>,
+    shutdown_timeout: Duration,
 ) -> Result<LanguageServerTransport, String> {
-    info!(
-        to_editor,
-        "Starting language server {server_name} as `{}`",
-        Some(cmd)
-            .into_iter()
-            .chain(args.iter().map(|s| s.as_str()))
-            .join(" ")
-    );
-    let mut child = match Command::new(cmd)
-        .args(args)
-        .envs(envs)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
-        Ok(c) => c,
-        Err(err) => {
-            return Err(match err.kind() {
-                ErrorKind::NotFound | ErrorKind::PermissionDenied => format!("{}: {}", err, cmd),
-                _ => format!("{}", err),
-            })
+    let address = address.map(parse_address).transpose()?;
+    if cmd.is_none() && matches!(address, None | Some(SocketAddress::Tcp { port: None, .. })) {
+        return Err(format!(
+            "language server {server_name} needs a command to start it"
+        ));
+    }
+    if let Some(cmd) = cmd {
+        info!(
+            to_editor,
+            "Starting language server {server_name} as `{}`",
+            Some(cmd)
+                .into_iter()
+                .chain(args.iter().map(|s| s.as_str()))
+                .join(" ")
+        );
+    }
+    let mut child = match cmd {
+        Some(cmd) => Some(
+            Command::new(cmd)
+                .args(args)
+                .envs(envs)
+                .stdin(if address.is_none() {
+                    Stdio::piped()
+                } else {
+                    Stdio::null()
+                })
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|err| match err.kind() {
+                    ErrorKind::NotFound | ErrorKind::PermissionDenied => {
+                        format!("{}: {}", err, cmd)
+                    }
+                    _ => format!("{}", err),
+                })?,
+        ),
+        None => None,
+    };
+
+    let mut output_loggers = vec![];
+    if let Some(child) = &mut child {
+        let stderr = child.stderr.take().expect("Failed to open stderr");
+        output_loggers.push(log_output(to_editor, &server_name, "stderr", stderr));
+        if address.is_some() {
+            let stdout = child.stdout.take().expect("Failed to open stdout");
+            output_loggers.push(log_output(to_editor, &server_name, "stdout", stdout));
         }
+    }
+
+    let (reader_sender, reader_receiver) = crossbeam_channel::bounded(1);
+    let connection = match address {
+        None => {
+            let child = child.as_mut().unwrap();
+            let writer = BufWriter::new(child.stdin.take().expect("Failed to open stdin"));
+            let reader = BufReader::new(child.stdout.take().expect("Failed to open stdout"));
+            reader_sender
+                .send(Box::new(reader) as Box<dyn BufRead + Send>)
+                .unwrap();
+            Connection::Stdio(Box::new(writer))
+        }
+        Some(address) => Connection::Socket(
+            address,
+            output_loggers
+                .last()
+                .filter(|_| child.is_some())
+                .map(|stdout_logger| stdout_logger.receiver().clone()),
+        ),
     };
 
-    let writer = BufWriter::new(child.stdin.take().expect("Failed to open stdin"));
-    let reader = BufReader::new(child.stdout.take().expect("Failed to open stdout"));
+    let pid = child.as_ref().map(|child| child.id());
+    // Only locked for a moment, so waiting for the server to exit doesn't block other threads.
+    let child = child.map(|child| Arc::new(Mutex::new(child)));
+    let (abort_connect, connect_aborted) = crossbeam_channel::bounded(0);
+    let connect_error = Arc::new(Mutex::new(None));
+    let exit_status = Arc::new(Mutex::new(None));
 
     // NOTE 1024 is arbitrary
     let channel_capacity = 1024;
 
-    let stderr = BufReader::new(child.stderr.take().expect("Failed to open stderr"));
-    let errors = {
-        let server_name = server_name.clone();
-        Worker::spawn(
-            to_editor.clone(),
-            "Language server errors",
-            channel_capacity,
-            move |to_editor, receiver, _| {
-                if let Err(TryRecvError::Disconnected) = receiver.try_recv() {
-                    return;
-                }
-                let mut stderr = stderr.bytes();
-                loop {
-                    let mut line = vec![];
-                    loop {
-                        let b = match stderr.next() {
-                            Some(Ok(b)) => b,
-                            None => return,
-                            Some(Err(_)) => break,
-                        };
-                        if b == b'\n' {
-                            break;
-                        }
-                        line.push(b);
-                    }
-                    info!(
-                        &to_editor,
-                        "Language server {server_name} stderr: {}",
-                        String::from_utf8_lossy(&line)
-                    );
-                }
-            },
-        )
-    };
-
     let from_lang_server = {
         let server_name = server_name.clone();
+        let child = child.clone();
+        let exit_status = exit_status.clone();
         Worker::spawn(
             to_editor.clone(),
             "Messages from language server",
             channel_capacity,
             move |to_editor, receiver, sender| {
-                if let Err(msg) = reader_loop(&to_editor, server_name, reader, receiver, &sender) {
+                // Without a reader, we failed to connect.
+                let Ok(reader) = reader_receiver.recv() else {
+                    return;
+                };
+                if let Err(msg) = reader_loop(&to_editor, server_name, reader, &receiver, &sender) {
                     error!(&to_editor, "{}", msg);
                 }
+                // Unless the transport is being dropped, the server stopped talking on its own.
+                // Give it a moment to exit, so the crash can be reported with its status.
+                // If it keeps running, it is terminated when the transport is dropped.
+                if let (Some(child), Err(TryRecvError::Empty)) = (child, receiver.try_recv()) {
+                    if let Ok(Some(status)) = wait_until(&child, Instant::now() + EXIT_TIMEOUT) {
+                        *exit_status.lock().unwrap() = Some(status);
+                    }
+                }
             },
         )
     };
 
     let to_lang_server = {
         let server_name = server_name.clone();
+        let child = child.clone();
+        let connect_error = connect_error.clone();
         Worker::spawn(
             to_editor.clone(),
             "Messages to language server",
             channel_capacity,
             move |to_editor, receiver, _| {
-                if writer_loop(&to_editor, &server_name, writer, &receiver).is_err() {
-                    error!(&to_editor, "Failed to write message to language server");
+                let connected = match connection {
+                    Connection::Stdio(writer) => Ok((writer, None)),
+                    Connection::Socket(address, port_receiver) => connect(
+                        &to_editor,
+                        &server_name,
+                        &address,
+                        child.is_some(),
+                        port_receiver,
+                        &connect_aborted,
+                    )
+                    .and_then(|socket| {
+                        let reader =
+                            BufReader::new(socket.try_clone().map_err(|err| err.to_string())?);
+                        let writer =
+                            BufWriter::new(socket.try_clone().map_err(|err| err.to_string())?);
+                        let _ = reader_sender.send(Box::new(reader));
+                        Ok((Box::new(writer) as Box<dyn Write + Send>, Some(socket)))
+                    }),
+                };
+                if let Err(err) = &connected {
+                    *connect_error.lock().unwrap() = Some(err.clone());
+                }
+                // Stop the reader loop if there is nothing to read.
+                drop(reader_sender);
+                match connected {
+                    Ok((writer, socket)) => {
+                        if writer_loop(&to_editor, &server_name, writer, &receiver).is_err() {
+                            error!(&to_editor, "Failed to write message to language server");
+                        }
+                        // Unblock the reader loop.
+                        if let Some(socket) = socket {
+                            let _ = socket.shutdown();
+                        }
+                    }
+                    Err(_) => {
+                        if let Some(child) = &child {
+                            let _ = child.lock().unwrap().kill();
+                        }
+                    }
+                }
+                if let Some(child) = child {
+                    match wait_for_exit(&to_editor, &server_name, &child, shutdown_timeout) {
+                        Ok(status) => info!(
+                            &to_editor,
+                            "Language server {server_name} exited with status: {}", status
+                        ),
+                        Err(err) => error!(
+                            &to_editor,
+                            "Failed to wait for language server {server_name} to exit: {}", err
+                        ),
+                    }
                 }
-                let exit_code = child.wait().unwrap();
-                debug!(
-                    &to_editor,
-                    "Language server {server_name} exited with status: {}", exit_code
-                );
             },
         )
     };
 
     Ok(LanguageServerTransport {
+        _abort_connect: abort_connect,
         to_lang_server,
         from_lang_server,
-        _errors: errors,
+        _output_loggers: output_loggers,
+        pid,
+        connect_error,
+        exit_status,
+        stub_receiver: None,
     })
 }
 
+/// A transport to a language server that doesn't exist, for replaying a trace.
+/// The messages sent to the server are left in `stub_receiver`.
+pub fn stub(to_editor: &ToEditorSender) -> LanguageServerTransport {
+    let (receiver_tx, receiver_rx) = crossbeam_channel::bounded(1);
+    let (abort_connect, _) = crossbeam_channel::bounded(0);
+    let to_lang_server = Worker::spawn(
+        to_editor.clone(),
+        "Messages to stub language server",
+        1024, // arbitrary
+        move |_, receiver, _| receiver_tx.send(receiver).unwrap(),
+    );
+    // The stub server never says anything on its own.
+    let from_lang_server = Worker::spawn(
+        to_editor.clone(),
+        "Messages from stub language server",
+        0,
+        |_, _, _| (),
+    );
+    LanguageServerTransport {
+        _abort_connect: abort_connect,
+        to_lang_server,
+        from_lang_server,
+        _output_loggers: vec![],
+        pid: None,
+        connect_error: Arc::default(),
+        exit_status: Arc::default(),
+        stub_receiver: Some(receiver_rx.recv().unwrap()),
+    }
+}
+
+/// Wait for a server to exit. Send SIGTERM when it takes longer than `timeout`,
+/// and SIGKILL when that doesn't help either.
+fn wait_for_exit(
+    to_editor: &ToEditorSender,
+    server_name: &ServerName,
+    child: &Mutex<Child>,
+    timeout: Duration,
+) -> io::Result<ExitStatus> {
+    if let Some(status) = wait_until(child, Instant::now() + timeout)? {
+        return Ok(status);
+    }
+    warn!(
+        to_editor,
+        "Language server {server_name} did not exit in time, terminating it"
+    );
+    unsafe {
+        libc::kill(child.lock().unwrap().id() as libc::pid_t, libc::SIGTERM);
+    }
+    if let Some(status) = wait_until(child, Instant::now() + KILL_TIMEOUT)? {
+        return Ok(status);
+    }
+    warn!(
+        to_editor,
+        "Language server {server_name} did not terminate, killing it"
+    );
+    child.lock().unwrap().kill()?;
+    wait_until(child, Instant::now() + KILL_TIMEOUT)?
+        .ok_or_else(|| Error::new(ErrorKind::TimedOut, "still running after SIGKILL"))
+}
+
+/// Poll the child until it exits, without keeping it locked in between.
+fn wait_until(child: &Mutex<Child>, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.lock().unwrap().try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Connect to a Unix socket, giving up after the timeout. A blocking connect would wait
+/// for as long as the listener's backlog is full.
+fn connect_unix(path: &Path, timeout: Duration) -> io::Result<UnixStream> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match mio::net::UnixStream::connect(path) {
+            Ok(stream) => {
+                let stream = UnixStream::from(stream);
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(Error::new(ErrorKind::TimedOut, "connection timed out"));
+                }
+                thread::sleep(CONNECT_RETRY_INTERVAL);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Connect to a language server. A server we just spawned may need some time to start
+/// listening, so keep trying until the timeout, or until the transport is dropped.
+fn connect(
+    to_editor: &ToEditorSender,
+    server_name: &ServerName,
+    address: &SocketAddress,
+    spawned: bool,
+    port_receiver: Option<Receiver<u16>>,
+    aborted: &Receiver<Void>,
+) -> Result<Socket, String> {
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    // Waits before the next try, and tells whether to give up.
+    let give_up = || match aborted.recv_timeout(CONNECT_RETRY_INTERVAL) {
+        Err(RecvTimeoutError::Timeout) => Instant::now() >= deadline,
+        _ => true,
+    };
+    let port = match address {
+        SocketAddress::Tcp { port: None, .. } => {
+            let port_receiver = port_receiver.expect("stdout of spawned server");
+            loop {
+                if let Ok(port) = port_receiver.try_recv() {
+                    break Some(port);
+                }
+                if give_up() {
+                    return Err("did not print a port to connect to".to_string());
+                }
+            }
+        }
+        SocketAddress::Tcp { port, .. } => *port,
+        SocketAddress::Unix(_) => None,
+    };
+    info!(
+        to_editor,
+        "Connecting to language server {server_name} at {}",
+        match address {
+            SocketAddress::Tcp { host, .. } => format!("{}:{}", host, port.unwrap()),
+            SocketAddress::Unix(path) => path.display().to_string(),
+        }
+    );
+    loop {
+        let timeout = deadline
+            .saturating_duration_since(Instant::now())
+            .max(CONNECT_RETRY_INTERVAL);
+        match Socket::connect(address, port, timeout) {
+            Ok(socket) => return Ok(socket),
+            Err(err) if spawned && !give_up() => {
+                debug!(
+                    to_editor,
+                    "Language server {server_name} is not accepting connections yet: {}", err
+                );
+            }
+            Err(err) => return Err(format!("failed to connect: {err}")),
+        }
+    }
+}
+
+/// Log the lines of a server's output stream. Sends the first port mentioned in it,
+/// for servers that pick one themselves.
+fn log_output(
+    to_editor: &ToEditorSender,
+    server_name: &ServerName,
+    stream_name: &'static str,
+    stream: impl Read + Send + 'static,
+) -> Worker<ToEditorSender, Void, u16> {
+    let server_name = server_name.clone();
+    Worker::spawn(
+        to_editor.clone(),
+        "Language server output",
+        1,
+        move |to_editor, receiver, sender| {
+            if let Err(TryRecvError::Disconnected) = receiver.try_recv() {
+                return;
+            }
+            let mut sent_port = false;
+            let mut stream = BufReader::new(stream).bytes();
+            loop {
+                let mut line = vec![];
+                loop {
+                    let b = match stream.next() {
+                        Some(Ok(b)) => b,
+                        None => return,
+                        Some(Err(_)) => break,
+                    };
+                    if b == b'\n' {
+                        break;
+                    }
+                    line.push(b);
+                }
+                let line = String::from_utf8_lossy(&line);
+                info!(
+                    &to_editor,
+                    "Language server {server_name} {stream_name}: {}", line
+                );
+                if !sent_port {
+                    if let Some(port) = port_in_line(&line) {
+                        sent_port = sender.send(port).is_ok();
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// The port in a line like "Listening on port 6008", or a line with only the port.
+/// Other numbers, like version numbers, are not ports.
+fn port_in_line(line: &str) -> Option<u16> {
+    let line = line.trim();
+    if let Ok(port) = line.parse() {
+        return Some(port);
+    }
+    let line = line.to_ascii_lowercase();
+    line.match_indices("port")
+        .filter(|(i, _)| !line[..*i].ends_with(|c: char| c.is_ascii_alphanumeric()))
+        .find_map(|(i, word)| {
+            let rest = line[i + word.len()..]
+                .trim_start_matches(|c: char| c == ':' || c == '=' || c.is_whitespace());
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .map_or(rest, |end| &rest[..end]);
+            digits.parse().ok()
+        })
+}
+
 fn reader_loop(
     to_editor: &ToEditorSender,
     server_name: ServerName,
     mut reader: impl BufRead,
-    receiver: Receiver<Void>,
+    receiver: &Receiver<Void>,
     sender: &Sender<ServerMessage>,
 ) -> io::Result<()> {
     let mut headers: HashMap<String, String> = HashMap::default();
@@ -174,20 +646,22 @@ fn reader_loop(
             .map_err(|_| Error::other("Failed to read content as UTF-8 string"))?;
         debug!(to_editor, "From server {server_name}: {msg}");
         let output: serde_json::Result<Output> = serde_json::from_str(&msg);
-        match output {
-            Ok(output) => {
-                if sender.send(ServerMessage::Response(output)).is_err() {
-                    return Err(Error::other("Failed to send response"));
-                }
-            }
+        let message = match output {
+            Ok(output) => ServerMessage::Response(output),
             Err(_) => {
                 let msg: Call = serde_json::from_str(&msg)
                     .map_err(|_| Error::other("Failed to parse language server message"))?;
-                if sender.send(ServerMessage::Request(msg)).is_err() {
-                    return Err(Error::other("Failed to send response"));
-                }
+                ServerMessage::Request(msg)
+            }
+        };
+        if trace::enabled() {
+            if let Err(err) = trace::server_message(&server_name, "receive", &message, &msg) {
+                error!(to_editor, "Failed to write trace, stopping it: {}", err);
             }
         }
+        if sender.send(message).is_err() {
+            return Err(Error::other("Failed to send response"));
+        }
     }
 }
 
@@ -197,12 +671,17 @@ fn writer_loop(
     mut writer: impl Write,
     receiver: &Receiver<ServerMessage>,
 ) -> io::Result<()> {
-    for request in receiver {
-        let request = match request {
-            ServerMessage::Request(request) => serde_json::to_string(&request),
-            ServerMessage::Response(response) => serde_json::to_string(&response),
+    for message in receiver {
+        let request = match &message {
+            ServerMessage::Request(request) => serde_json::to_string(request),
+            ServerMessage::Response(response) => serde_json::to_string(response),
         }?;
         debug!(to_editor, "To server {server_name}: {request}",);
+        if trace::enabled() {
+            if let Err(err) = trace::server_message(server_name, "send", &message, &request) {
+                error!(to_editor, "Failed to write trace, stopping it: {}", err);
+            }
+        }
         write!(
             writer,
             "Content-Length: {}\r\n\r\n{}",
@@ -211,11 +690,337 @@ fn writer_loop(
         )?;
         writer.flush()?;
     }
-    // NOTE we rely on the assumption that language server will exit when its stdin is closed
-    // without need to kill child process
+    // NOTE a language server should exit after the exit notification, or at the latest when its
+    // stdin is closed, else it is terminated after the shutdown timeout.
     debug!(
         to_editor,
         "Received signal to stop language server, closing pipe"
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor_transport::mock_to_editor;
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixListener;
+
+    /// Stand-in language server: answers one request with a null result.
+    fn serve_one_request(mut stream: impl Read + Write) {
+        let mut reader = BufReader::new(&mut stream);
+        let mut content_len = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(len) = header.strip_prefix("Content-Length: ") {
+                content_len = len.parse().unwrap();
+            }
+        }
+        let mut content = vec![0; content_len];
+        reader.read_exact(&mut content).unwrap();
+        let request: serde_json::Value = serde_json::from_slice(&content).unwrap();
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": null,
+        })
+        .to_string();
+        write!(
+            stream,
+            "Content-Length: {}\r\n\r\n{}",
+            response.len(),
+            response
+        )
+        .unwrap();
+    }
+
+    fn round_trip(transport: &LanguageServerTransport) {
+        let request: Call = serde_json::from_str(
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "shutdown", "params": null}"#,
+        )
+        .unwrap();
+        transport
+            .to_lang_server
+            .sender()
+            .send(ServerMessage::Request(request))
+            .unwrap();
+        let response = transport
+            .from_lang_server
+            .receiver()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        let ServerMessage::Response(Output::Success(success)) = response else {
+            panic!("unexpected message");
+        };
+        assert_eq!(success.id, jsonrpc_core::Id::Num(1));
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kak-lsp-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn parse_address_tcp() {
+        assert_eq!(
+            parse_address("tcp://localhost:6005"),
+            Ok(SocketAddress::Tcp {
+                host: "localhost".to_string(),
+                port: Some(6005)
+            })
+        );
+        assert_eq!(
+            parse_address("tcp://[::1]:6005"),
+            Ok(SocketAddress::Tcp {
+                host: "::1".to_string(),
+                port: Some(6005)
+            })
+        );
+        assert_eq!(
+            parse_address("tcp://127.0.0.1"),
+            Ok(SocketAddress::Tcp {
+                host: "127.0.0.1".to_string(),
+                port: None
+            })
+        );
+        assert!(parse_address("tcp://localhost:http").is_err());
+        assert!(parse_address("tcp://:6005").is_err());
+    }
+
+    #[test]
+    fn parse_address_unix() {
+        assert_eq!(
+            parse_address("unix:/run/clangd.sock"),
+            Ok(SocketAddress::Unix(PathBuf::from("/run/clangd.sock")))
+        );
+        assert!(parse_address("unix:").is_err());
+        assert!(parse_address("localhost:6005").is_err());
+    }
+
+    #[test]
+    fn port_in_output_line() {
+        assert_eq!(port_in_line("Listening on port 40123"), Some(40123));
+        assert_eq!(port_in_line("Port: 40123"), Some(40123));
+        assert_eq!(port_in_line("port=40123 (tcp)"), Some(40123));
+        assert_eq!(port_in_line(" 40123 "), Some(40123));
+        assert_eq!(port_in_line("clangd version 17.0.1"), None);
+        assert_eq!(port_in_line("report 3 errors"), None);
+        assert_eq!(port_in_line("ports 40123"), None);
+        assert_eq!(port_in_line("port 99999"), None);
+        assert_eq!(port_in_line("starting up"), None);
+    }
+
+    #[test]
+    fn connect_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || serve_one_request(listener.accept().unwrap().0));
+        let transport = start(
+            &mock_to_editor(),
+            "test".to_string(),
+            None,
+            &[],
+            &HashMap::new(),
+            Some(&format!("tcp://127.0.0.1:{port}")),
+            Duration::ZERO,
+        )
+        .unwrap();
+        round_trip(&transport);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connect_unix() {
+        let path = socket_path("connect_unix");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || serve_one_request(listener.accept().unwrap().0));
+        let transport = start(
+            &mock_to_editor(),
+            "test".to_string(),
+            None,
+            &[],
+            &HashMap::new(),
+            Some(&format!("unix:{}", path.display())),
+            Duration::ZERO,
+        )
+        .unwrap();
+        round_trip(&transport);
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connect_unix_times_out_while_backlog_is_full() {
+        let path = socket_path("connect_unix_times_out");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        // Listening again shrinks the backlog, so that it fills up quickly.
+        assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+        let mut pending = vec![];
+        let full = loop {
+            match mio::net::UnixStream::connect(&path) {
+                Ok(stream) => pending.push(stream),
+                Err(err) => break err,
+            }
+            assert!(pending.len() < 16, "backlog never filled up");
+        };
+        assert_eq!(full.kind(), ErrorKind::WouldBlock);
+        let started = Instant::now();
+        let timeout = Duration::from_millis(200);
+        let err = Socket::connect(&SocketAddress::Unix(path.clone()), None, timeout)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() >= timeout);
+        assert!(started.elapsed() < CONNECT_TIMEOUT);
+
+        // Once the server accepts a connection, connecting succeeds again.
+        let _accepted = listener.accept().unwrap();
+        assert!(Socket::connect(&SocketAddress::Unix(path.clone()), None, timeout).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn spawn_then_connect_to_printed_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || serve_one_request(listener.accept().unwrap().0));
+        let transport = start(
+            &mock_to_editor(),
+            "test".to_string(),
+            Some("sh"),
+            &["-c".to_string(), format!("echo 'listening on port {port}'")],
+            &HashMap::new(),
+            Some("tcp://127.0.0.1"),
+            Duration::ZERO,
+        )
+        .unwrap();
+        round_trip(&transport);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connect_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let transport = start(
+            &mock_to_editor(),
+            "test".to_string(),
+            None,
+            &[],
+            &HashMap::new(),
+            Some(&format!("tcp://127.0.0.1:{port}")),
+            Duration::ZERO,
+        )
+        .unwrap();
+        // Failing to connect looks like a crash.
+        assert!(transport.from_lang_server.receiver().recv().is_err());
+        assert!(transport
+            .connect_error()
+            .is_some_and(|err| err.starts_with("failed to connect")));
+    }
+
+    #[test]
+    fn connect_in_background() {
+        let started = Instant::now();
+        let transport = start(
+            &mock_to_editor(),
+            "test".to_string(),
+            Some("sh"),
+            &[
+                "-c".to_string(),
+                "echo 'version 1.2.3'; exec sleep 30".to_string(),
+            ],
+            &HashMap::new(),
+            Some("tcp://127.0.0.1"),
+            Duration::ZERO,
+        )
+        .unwrap();
+        transport
+            .to_lang_server
+            .sender()
+            .send(ServerMessage::Request(Call::Invalid {
+                id: jsonrpc_core::Id::Null,
+            }))
+            .unwrap();
+        // Dropping the transport stops waiting for the server to print its port.
+        drop(transport);
+        assert!(started.elapsed() < CONNECT_TIMEOUT);
+    }
+
+    #[test]
+    fn exit_status_of_crashed_server() {
+        let transport = start(
+            &mock_to_editor(),
+            "test".to_string(),
+            Some("sh"),
+            &["-c".to_string(), "exit 3".to_string()],
+            &HashMap::new(),
+            None,
+            Duration::ZERO,
+        )
+        .unwrap();
+        // The reader stops when the server closes its output.
+        assert!(transport
+            .from_lang_server
+            .receiver()
+            .recv_timeout(Duration::from_secs(5))
+            .is_err());
+        assert_eq!(
+            transport.exit_status().and_then(|status| status.code()),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn server_that_closes_its_output_keeps_running() {
+        let transport = start(
+            &mock_to_editor(),
+            "test".to_string(),
+            Some("sh"),
+            &["-c".to_string(), "exec >&-; exec sleep 30".to_string()],
+            &HashMap::new(),
+            None,
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert!(transport
+            .from_lang_server
+            .receiver()
+            .recv_timeout(Duration::from_secs(5))
+            .is_err());
+        let started = Instant::now();
+        assert_eq!(transport.exit_status(), None);
+        assert!(started.elapsed() < Duration::from_millis(100));
+        // It's up to whoever drops the transport to stop the server.
+        let pid = transport.pid().unwrap() as libc::pid_t;
+        assert_eq!(unsafe { libc::kill(pid, 0) }, 0);
+    }
+
+    #[test]
+    fn kill_server_that_ignores_sigterm() {
+        let transport = start(
+            &mock_to_editor(),
+            "test".to_string(),
+            Some("sh"),
+            &[
+                "-c".to_string(),
+                "trap '' TERM; while true; do true; done".to_string(),
+            ],
+            &HashMap::new(),
+            None,
+            Duration::from_millis(100),
+        )
+        .unwrap();
+        let start = Instant::now();
+        drop(transport);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -16,14 +16,17 @@ mod editor_transport;
 mod language_features;
 mod language_server_transport;
 mod markup;
+mod metrics;
 mod position;
 mod progress;
 mod project_root;
 mod settings;
 mod show_message;
+mod status;
 mod text_edit;
 mod text_sync;
 mod thread_worker;
+mod trace;
 mod types;
 mod util;
 mod wcwidth;
@@ -176,6 +179,16 @@ fn run_main() -> Result<(), ()> {
                 .long("version")
                 .action(ArgAction::SetTrue)
                 .help("print version"),
+        )
+        .subcommand(
+            clap::Command::new("replay")
+                .about("replay a trace written with the 'lsp_trace_file' option")
+                .arg(
+                    Arg::new("trace")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("trace file to replay"),
+                ),
         );
     let matches = command.clone().get_matches();
 
@@ -194,6 +207,10 @@ fn run_main() -> Result<(), ()> {
         };
     }
 
+    if let Some(("replay", replay_matches)) = matches.subcommand() {
+        return replay(&matches, replay_matches);
+    }
+
     let kak_session = environment_variable(None, "kak_session")?;
     let externally_started = kak_session.is_none();
 
@@ -380,6 +397,33 @@ fn run_main() -> Result<(), ()> {
                 .parse()
                 .map_err(|err| fatal_error(format!("failed to parse lsp_timeout: {err}")))?;
         }
+        if let Some(shutdown_timeout) = env_var("kak_opt_lsp_shutdown_timeout")? {
+            config.server.shutdown_timeout = shutdown_timeout.parse().map_err(|err| {
+                fatal_error(format!("failed to parse lsp_shutdown_timeout: {err}"))
+            })?;
+        }
+        if let Some(threshold) = env_var("kak_opt_lsp_slow_request_threshold")? {
+            config.server.slow_request_threshold = threshold.parse().map_err(|err| {
+                fatal_error(format!("failed to parse lsp_slow_request_threshold: {err}"))
+            })?;
+        }
+        if let Some(request_timeouts) = env_var("kak_opt_lsp_request_timeouts")? {
+            config.server.request_timeouts =
+                parse_request_timeouts(&request_timeouts).map_err(|err| {
+                    fatal_error(format!("failed to parse lsp_request_timeouts: {err}"))
+                })?;
+        }
+        if let Some(metrics_file) = env_var("kak_opt_lsp_metrics_file")? {
+            config.server.metrics_file =
+                (!metrics_file.is_empty()).then(|| PathBuf::from(metrics_file));
+        }
+        if let Some(trace_file) = env_var("kak_opt_lsp_trace_file")? {
+            config.server.trace_file = (!trace_file.is_empty()).then(|| PathBuf::from(trace_file));
+        }
+        if let Some(server_trace) = env_var("kak_opt_lsp_server_trace")? {
+            config.server.server_trace = parse_server_trace(&server_trace)
+                .map_err(|err| fatal_error(format!("failed to parse lsp_server_trace: {err}")))?;
+        }
         if let Some(snippet_support) = env_var("kak_opt_lsp_snippet_support")? {
             config.snippet_support = snippet_support != "false";
         }
@@ -482,6 +526,16 @@ fn run_main() -> Result<(), ()> {
         }));
     }
 
+    if let Some(trace_file) = &config.server.trace_file {
+        trace::open(trace_file).map_err(|err| {
+            fatal_error(format!(
+                "failed to open trace file '{}': {}",
+                trace_file.display(),
+                err
+            ))
+        })?;
+    }
+
     controller::start(
         session.clone(),
         config,
@@ -552,6 +606,20 @@ fn kakoune() -> Result<(), ()> {
     }
 }
 
+/// Replay a trace against stub language servers, see `controller::replay`.
+fn replay(matches: &ArgMatches, replay_matches: &ArgMatches) -> Result<(), ()> {
+    let path = Path::new(replay_matches.get_one::<String>("trace").unwrap());
+    let entries = trace::load(path).map_err(|err| eprintln!("Error reading trace: {}", err))?;
+    if matches.get_flag("debug") {
+        DEBUG.store(true, Relaxed);
+    }
+    let mut config = Config::default();
+    // Stub servers won't respond to the shutdown request.
+    config.server.shutdown_timeout = 0;
+    controller::replay(config, entries, &mut stdout());
+    Ok(())
+}
+
 fn report_fatal_error(session: Option<&SessionId>, message: &str) {
     let Some(session) = session else {
         eprintln!("{}", message);
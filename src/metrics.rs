@@ -0,0 +1,195 @@
+//! Latency of requests to language servers, per server and method.
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::types::ServerName;
+
+/// Histogram buckets grow by a factor of 2^(1/4), from 1ms to 2^(BUCKETS/4) ms (about 65s).
+/// Slower requests are counted in the last bucket.
+const BUCKETS_PER_DOUBLING: usize = 4;
+const BUCKETS: usize = 16 * BUCKETS_PER_DOUBLING;
+
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    max: Duration,
+    /// Requests that were given up on because the server didn't respond in time.
+    pub timeouts: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: Duration::ZERO,
+            timeouts: 0,
+        }
+    }
+}
+
+fn bucket_upper_bound_ms(bucket: usize) -> f64 {
+    2_f64.powf((bucket + 1) as f64 / BUCKETS_PER_DOUBLING as f64)
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        let bucket = (0..BUCKETS)
+            .find(|&bucket| ms < bucket_upper_bound_ms(bucket))
+            .unwrap_or(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    /// An upper bound of the latency of the given fraction of requests, accurate to ~19%.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((self.count as f64 * fraction).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                if bucket == BUCKETS - 1 {
+                    break;
+                }
+                let upper_bound = Duration::from_secs_f64(bucket_upper_bound_ms(bucket) / 1000.0);
+                return upper_bound.min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+#[derive(Serialize)]
+struct MethodSummary<'a> {
+    server: &'a str,
+    method: &'a str,
+    count: u64,
+    p50_ms: u128,
+    p95_ms: u128,
+    max_ms: u128,
+    timeouts: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub latencies: BTreeMap<(ServerName, &'static str), LatencyHistogram>,
+}
+
+impl Metrics {
+    pub fn histogram(&mut self, server_name: &str, method: &'static str) -> &mut LatencyHistogram {
+        self.latencies
+            .entry((server_name.to_string(), method))
+            .or_default()
+    }
+
+    fn summaries(&self) -> impl Iterator<Item = MethodSummary<'_>> {
+        self.latencies
+            .iter()
+            .map(|((server, method), histogram)| MethodSummary {
+                server,
+                method,
+                count: histogram.count,
+                p50_ms: histogram.percentile(0.5).as_millis(),
+                p95_ms: histogram.percentile(0.95).as_millis(),
+                max_ms: histogram.max.as_millis(),
+                timeouts: histogram.timeouts,
+            })
+    }
+
+    /// A table with one line per server and method.
+    pub fn table(&self) -> String {
+        let header =
+            ["server", "method", "count", "p50", "p95", "max", "timeouts"].map(String::from);
+        let rows: Vec<[String; 7]> = std::iter::once(header)
+            .chain(self.summaries().map(|summary| {
+                [
+                    summary.server.to_string(),
+                    summary.method.to_string(),
+                    summary.count.to_string(),
+                    format!("{}ms", summary.p50_ms),
+                    format!("{}ms", summary.p95_ms),
+                    format!("{}ms", summary.max_ms),
+                    summary.timeouts.to_string(),
+                ]
+            }))
+            .collect();
+        let widths: Vec<usize> = (0..7)
+            .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap())
+            .collect();
+        rows.iter()
+            .map(|row| {
+                row.iter()
+                    .enumerate()
+                    .map(|(column, cell)| {
+                        // Left-align the names, right-align the numbers.
+                        if column < 2 {
+                            format!("{:<width$}", cell, width = widths[column])
+                        } else {
+                            format!("{:>width$}", cell, width = widths[column])
+                        }
+                    })
+                    .join("  ")
+                    .trim_end()
+                    .to_string()
+                    + "\n"
+            })
+            .collect()
+    }
+
+    /// One JSON object per server and method.
+    pub fn json_lines(&self) -> String {
+        self.summaries()
+            .map(|summary| serde_json::to_string(&summary).unwrap() + "\n")
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        let p50 = histogram.percentile(0.5).as_millis();
+        let p95 = histogram.percentile(0.95).as_millis();
+        assert!((50..=60).contains(&p50), "p50 is {}ms", p50);
+        assert!((95..=100).contains(&p95), "p95 is {}ms", p95);
+        assert_eq!(histogram.percentile(1.0), Duration::from_millis(100));
+        assert_eq!(histogram.max, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn percentiles_of_outliers() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::ZERO);
+        assert_eq!(histogram.percentile(0.5), Duration::ZERO);
+        histogram.record(Duration::from_secs(600));
+        assert_eq!(histogram.percentile(0.95), Duration::from_secs(600));
+        assert_eq!(LatencyHistogram::default().percentile(0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn table() {
+        let mut metrics = Metrics::default();
+        metrics
+            .histogram("rust-analyzer", "textDocument/hover")
+            .record(Duration::from_millis(20));
+        assert_eq!(
+            metrics.table(),
+            "server         method              count   p50   p95   max  timeouts\n\
+             rust-analyzer  textDocument/hover      1  20ms  20ms  20ms         0\n"
+        );
+    }
+}
@@ -18,6 +18,10 @@
 //! adhere to the spec. This might cause breakage with servers that neither adhere to the spec
 //! nor implement UTF-8 byte offsets (see https://clangd.llvm.org/extensions.html#utf-8-offsets).
 //! Hopefully there are not too many of these servers left.
+//! Lines are terminated by LF, CRLF or a lone CR, which is what LSP prescribes. Other Unicode
+//! line separators such as form feed, NEL or U+2028 don't start a new line. Kakoune only
+//! splits lines at LF, so the conversions below map lines that follow a lone CR onto the
+//! Kakoune line they continue.
 use crate::context::ServerSettings;
 use crate::types::*;
 use crate::{context::Context, util::read_document};
@@ -33,9 +37,14 @@ pub fn lsp_range_to_kakoune(
     text: &Rope,
     offset_encoding: OffsetEncoding,
 ) -> KakouneRange {
-    match offset_encoding {
+    let range = match offset_encoding {
         OffsetEncoding::Utf8 => lsp_range_to_kakoune_utf_8(range),
         OffsetEncoding::Utf16 => lsp_range_to_kakoune_utf_16(range, text),
+        OffsetEncoding::Utf32 => lsp_range_to_kakoune_utf_32(range, text),
+    };
+    KakouneRange {
+        start: rope_position_to_kakoune(range.start, text),
+        end: rope_position_to_kakoune(range.end, text),
     }
 }
 
@@ -44,10 +53,12 @@ pub fn lsp_position_to_kakoune(
     text: &Rope,
     offset_encoding: OffsetEncoding,
 ) -> KakounePosition {
-    match offset_encoding {
+    let position = match offset_encoding {
         OffsetEncoding::Utf8 => lsp_position_to_kakoune_utf_8(position),
         OffsetEncoding::Utf16 => lsp_position_to_kakoune_utf_16(position, text),
-    }
+        OffsetEncoding::Utf32 => lsp_position_to_kakoune_utf_32(position, text),
+    };
+    rope_position_to_kakoune(position, text)
 }
 
 pub fn kakoune_range_to_lsp(
@@ -66,12 +77,125 @@ pub fn kakoune_position_to_lsp(
     text: &Rope,
     offset_encoding: OffsetEncoding,
 ) -> Position {
+    let position = &kakoune_position_to_rope(position, text);
     match offset_encoding {
         OffsetEncoding::Utf8 => kakoune_position_to_lsp_utf_8(position),
         OffsetEncoding::Utf16 => kakoune_position_to_lsp_utf_16(position, text),
+        OffsetEncoding::Utf32 => kakoune_position_to_lsp_utf_32(position, text),
+    }
+}
+
+/// Convert a 0-based LSP line to the 1-based Kakoune line that contains it.
+pub fn lsp_line_to_kakoune(line: u32, text: &Rope) -> u32 {
+    let rope_line = min(line as usize, text.len_lines());
+    line + 1 - lone_crs_before(rope_line, text)
+}
+
+/// Move a 1-based position on the Rope's lines, which end at a lone CR like LSP lines do,
+/// onto Kakoune's lines, which don't.
+fn rope_position_to_kakoune(position: KakounePosition, text: &Rope) -> KakounePosition {
+    let rope_line = position.line.saturating_sub(1) as usize;
+    if rope_line >= text.len_lines() {
+        return KakounePosition {
+            line: lsp_line_to_kakoune(position.line.saturating_sub(1), text),
+            column: position.column,
+        };
+    }
+    let lone_crs = lone_crs_before(rope_line, text);
+    let line = text.line(rope_line);
+    let mut column = position.column;
+    // Selecting up to the end of the line means up to the lone CR, not the end of the
+    // Kakoune line that continues after it.
+    if ends_with_lone_cr(line) {
+        column = column.min(line.len_bytes() as u32);
+    }
+    if lone_crs != 0 {
+        let mut start = rope_line;
+        while start > 0 && ends_with_lone_cr(text.line(start - 1)) {
+            start -= 1;
+        }
+        let offset = text.line_to_byte(rope_line) - text.line_to_byte(start);
+        column = column.saturating_add(offset as u32);
+    }
+    KakounePosition {
+        line: position.line - lone_crs,
+        column,
+    }
+}
+
+/// The inverse of rope_position_to_kakoune.
+fn kakoune_position_to_rope(position: &KakounePosition, text: &Rope) -> KakounePosition {
+    let kakoune_line = position.line.saturating_sub(1) as usize;
+    // Find the first Rope line of this Kakoune line, which is preceded by as many lone CRs
+    // as it is ahead of the Kakoune line.
+    let mut rope_line = kakoune_line;
+    while rope_line < text.len_lines() {
+        let next = kakoune_line + lone_crs_before(rope_line, text) as usize;
+        if next == rope_line {
+            break;
+        }
+        rope_line = next;
+    }
+    if rope_line >= text.len_lines() {
+        return KakounePosition {
+            line: rope_line as u32 + 1,
+            column: position.column,
+        };
+    }
+    let mut column = position.column.saturating_sub(1) as usize;
+    loop {
+        let line = text.line(rope_line);
+        if !ends_with_lone_cr(line) || column < line.len_bytes() {
+            break;
+        }
+        column -= line.len_bytes();
+        rope_line += 1;
+    }
+    KakounePosition {
+        line: rope_line as u32 + 1,
+        column: column as u32 + 1,
     }
 }
 
+/// Count the lines before the given Rope line that end in a lone CR.
+fn lone_crs_before(rope_line: usize, text: &Rope) -> u32 {
+    let end = text.line_to_byte(rope_line);
+    let mut count = 0;
+    let mut after_cr = false;
+    for chunk in text.byte_slice(..end).chunks() {
+        let bytes = chunk.as_bytes();
+        if bytes.is_empty() {
+            continue;
+        }
+        if after_cr && bytes[0] != b'\n' {
+            count += 1;
+        }
+        after_cr = false;
+        if !bytes.contains(&b'\r') {
+            continue;
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            if *byte != b'\r' {
+                continue;
+            }
+            match bytes.get(i + 1) {
+                Some(b'\n') => {}
+                Some(_) => count += 1,
+                None => after_cr = true,
+            }
+        }
+    }
+    // Line starts follow line terminators, so a CR right before one is lone.
+    if after_cr {
+        count += 1;
+    }
+    count
+}
+
+fn ends_with_lone_cr(line: RopeSlice) -> bool {
+    line.len_chars() > 0 && line.char(line.len_chars() - 1) == '\r'
+}
+
 /// Wrapper for kakoune_position_to_lsp which uses context to get buffer content and offset encoding.
 pub fn get_lsp_position(
     server: &ServerSettings,
@@ -198,16 +322,33 @@ pub fn ranges_overlap(a: Range, b: Range) -> bool {
     a.start.max(b.start) <= a.end.min(b.end)
 }
 
-/// Get the byte index of a character in a Rope slice
+/// Get the byte index of a UTF-16 code unit in a Rope slice
 ///
 /// If the char number is out-of-bounds, this will return one past
 /// the last character. This is useful because the language
 /// server might use a large value to convey "end of file".
-fn get_byte_index(char_index: usize, text: RopeSlice) -> usize {
+fn get_byte_index_utf_16(char_index: usize, text: RopeSlice) -> usize {
     text.char_to_byte(text.utf16_cu_to_char(min(char_index, text.len_utf16_cu())))
 }
 
+/// Like get_byte_index_utf_16 but for a code point.
+fn get_byte_index_utf_32(char_index: usize, text: RopeSlice) -> usize {
+    text.char_to_byte(min(char_index, text.len_chars()))
+}
+
 fn lsp_range_to_kakoune_utf_16(range: &Range, text: &Rope) -> KakouneRange {
+    lsp_range_to_kakoune_code_units(range, text, get_byte_index_utf_16)
+}
+
+fn lsp_range_to_kakoune_utf_32(range: &Range, text: &Rope) -> KakouneRange {
+    lsp_range_to_kakoune_code_units(range, text, get_byte_index_utf_32)
+}
+
+fn lsp_range_to_kakoune_code_units(
+    range: &Range,
+    text: &Rope,
+    get_byte_index: fn(usize, RopeSlice) -> usize,
+) -> KakouneRange {
     let Range { start, end } = range;
 
     let start_line = get_line(start.line as _, text);
@@ -322,6 +463,31 @@ fn kakoune_position_to_lsp_utf_16(position: &KakounePosition, text: &Rope) -> Po
     }
 }
 
+fn kakoune_position_to_lsp_utf_32(position: &KakounePosition, text: &Rope) -> Position {
+    // -1 because LSP & Rope ranges are 0-based, but Kakoune's are 1-based.
+    let line_idx = position.line - 1;
+    let col_idx = position.column - 1;
+    if line_idx as usize >= text.len_lines() {
+        return Position {
+            line: line_idx,
+            character: col_idx,
+        };
+    }
+
+    let line = text.line(line_idx as _);
+    if col_idx as usize >= line.len_bytes() {
+        return Position {
+            line: line_idx,
+            character: col_idx,
+        };
+    }
+
+    Position {
+        line: line_idx,
+        character: line.byte_to_char(col_idx as _) as _,
+    }
+}
+
 fn kakoune_position_to_lsp_utf_8(position: &KakounePosition) -> Position {
     // -1 because LSP ranges are 0-based, but Kakoune's are 1-based.
     Position {
@@ -354,6 +520,30 @@ fn lsp_position_to_kakoune_utf_16(position: &Position, text: &Rope) -> KakounePo
     }
 }
 
+fn lsp_position_to_kakoune_utf_32(position: &Position, text: &Rope) -> KakounePosition {
+    if position.line as usize >= text.len_lines() {
+        return KakounePosition {
+            line: position.line + 1,
+            column: 999999999,
+        };
+    }
+
+    let line = text.line(position.line as _);
+    if position.character as usize >= line.len_chars() {
+        return KakounePosition {
+            line: position.line + 1,
+            column: 999999999,
+        };
+    }
+
+    let byte = line.char_to_byte(position.character as _) as u32;
+    // +1 because LSP ranges are 0-based, but Kakoune's are 1-based.
+    KakounePosition {
+        line: position.line + 1,
+        column: byte + 1,
+    }
+}
+
 fn lsp_position_to_kakoune_utf_8(position: &Position) -> KakounePosition {
     // +1 because LSP ranges are 0-based, but Kakoune's are 1-based.
     KakounePosition {
@@ -370,6 +560,7 @@ pub fn lsp_character_to_byte_offset(
     match offset_encoding {
         OffsetEncoding::Utf8 => lsp_character_to_byte_offset_utf_8(line, character),
         OffsetEncoding::Utf16 => lsp_character_to_byte_offset_utf_16(line, character),
+        OffsetEncoding::Utf32 => lsp_character_to_byte_offset_utf_32(line, character),
     }
 }
 
@@ -381,6 +572,14 @@ fn lsp_character_to_byte_offset_utf_16(line: RopeSlice, character: usize) -> Opt
     }
 }
 
+fn lsp_character_to_byte_offset_utf_32(line: RopeSlice, character: usize) -> Option<usize> {
+    if character <= line.len_chars() {
+        Some(line.char_to_byte(character))
+    } else {
+        None
+    }
+}
+
 fn lsp_character_to_byte_offset_utf_8(line: RopeSlice, character: usize) -> Option<usize> {
     if character <= line.len_bytes() {
         Some(character)
@@ -464,4 +663,244 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn lsp_range_to_kakoune_utf_32_bol_insert() {
+        let text = Rope::from_str(&"x\n".repeat(12));
+        assert_eq!(
+            lsp_range_to_kakoune_utf_32(
+                &Range {
+                    start: Position {
+                        line: 10,
+                        character: 0
+                    },
+                    end: Position {
+                        line: 10,
+                        character: 0
+                    }
+                },
+                &text
+            ),
+            KakouneRange {
+                start: KakounePosition {
+                    line: 11,
+                    column: 1
+                },
+                end: KakounePosition {
+                    line: 11,
+                    column: 1
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn lsp_range_to_kakoune_utf_32_bof_insert() {
+        let text = Rope::from_str("x\n");
+        assert_eq!(
+            lsp_range_to_kakoune_utf_32(
+                &Range {
+                    start: Position {
+                        line: 0,
+                        character: 0
+                    },
+                    end: Position {
+                        line: 0,
+                        character: 0
+                    }
+                },
+                &text
+            ),
+            KakouneRange {
+                start: KakounePosition { line: 1, column: 1 },
+                end: KakounePosition { line: 1, column: 1 }
+            }
+        );
+    }
+
+    #[test]
+    fn lsp_range_to_kakoune_utf_32_eol() {
+        let text = Rope::from_str(&"x\n".repeat(12));
+        assert_eq!(
+            lsp_range_to_kakoune_utf_32(
+                &Range {
+                    start: Position {
+                        line: 10,
+                        character: 0
+                    },
+                    end: Position {
+                        line: 11,
+                        character: 0
+                    }
+                },
+                &text
+            ),
+            KakouneRange {
+                start: KakounePosition {
+                    line: 11,
+                    column: 1
+                },
+                end: KakounePosition {
+                    line: 11,
+                    column: EOL_OFFSET
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn lsp_range_to_kakoune_utf_32_astral() {
+        // U+1F600 is 4 bytes, 2 UTF-16 code units and 1 code point.
+        let text = Rope::from_str("a\u{1F600}b\u{e9}c\n");
+        let range = Range {
+            start: Position {
+                line: 0,
+                character: 2,
+            },
+            end: Position {
+                line: 0,
+                character: 4,
+            },
+        };
+        assert_eq!(
+            lsp_range_to_kakoune(&range, &text, OffsetEncoding::Utf32),
+            KakouneRange {
+                start: KakounePosition { line: 1, column: 6 },
+                end: KakounePosition { line: 1, column: 8 }
+            }
+        );
+        let range = Range {
+            start: Position {
+                line: 0,
+                character: 3,
+            },
+            end: Position {
+                line: 0,
+                character: 5,
+            },
+        };
+        assert_eq!(
+            lsp_range_to_kakoune(&range, &text, OffsetEncoding::Utf16),
+            KakouneRange {
+                start: KakounePosition { line: 1, column: 6 },
+                end: KakounePosition { line: 1, column: 8 }
+            }
+        );
+    }
+
+    #[test]
+    fn kakoune_position_to_lsp_utf_32_astral() {
+        let text = Rope::from_str("a\u{1F600}b\u{e9}c\n");
+        // The "c" after the two-byte "é".
+        let position = KakounePosition { line: 1, column: 9 };
+        assert_eq!(
+            kakoune_position_to_lsp(&position, &text, OffsetEncoding::Utf32),
+            Position {
+                line: 0,
+                character: 4
+            }
+        );
+        assert_eq!(
+            kakoune_position_to_lsp(&position, &text, OffsetEncoding::Utf16),
+            Position {
+                line: 0,
+                character: 5
+            }
+        );
+        assert_eq!(
+            lsp_position_to_kakoune(
+                &Position {
+                    line: 0,
+                    character: 4
+                },
+                &text,
+                OffsetEncoding::Utf32
+            ),
+            position
+        );
+    }
+
+    #[test]
+    fn lsp_character_to_byte_offset_utf_32_astral() {
+        let text = Rope::from_str("a\u{1F600}b\n");
+        let line = text.line(0);
+        assert_eq!(
+            lsp_character_to_byte_offset(line, 2, OffsetEncoding::Utf32),
+            Some(5)
+        );
+        assert_eq!(
+            lsp_character_to_byte_offset(line, 3, OffsetEncoding::Utf16),
+            Some(5)
+        );
+        assert_eq!(
+            lsp_character_to_byte_offset(line, 5, OffsetEncoding::Utf32),
+            None
+        );
+    }
+
+    #[test]
+    fn lsp_position_to_kakoune_line_terminators() {
+        // Only LF, CRLF and CR end a line. Form feed, NEL and U+2028 don't.
+        let text = Rope::from_str("a\r\nb\u{c}\u{85}\u{2028}c\rd\ne");
+        let position = |line, character| Position { line, character };
+        assert_eq!(text.line(2), "d\n");
+        assert_eq!(
+            lsp_position_to_kakoune(&position(1, 4), &text, OffsetEncoding::Utf16),
+            KakounePosition { line: 2, column: 8 }
+        );
+        assert_eq!(
+            lsp_position_to_kakoune(&position(3, 0), &text, OffsetEncoding::Utf32),
+            KakounePosition { line: 3, column: 1 }
+        );
+        assert_eq!(
+            kakoune_position_to_lsp(
+                &KakounePosition { line: 3, column: 1 },
+                &text,
+                OffsetEncoding::Utf16
+            ),
+            position(3, 0)
+        );
+    }
+
+    #[test]
+    fn positions_after_lone_cr() {
+        // Kakoune shows "b\rc" as a single line.
+        let text = Rope::from_str("a\nb\rc\rd\ne\n");
+        let position = |line, character| Position { line, character };
+        let kakoune = |line, column| KakounePosition { line, column };
+        for encoding in [OffsetEncoding::Utf8, OffsetEncoding::Utf16] {
+            for (lsp, kak) in [
+                (position(1, 0), kakoune(2, 1)),
+                (position(1, 1), kakoune(2, 2)),
+                (position(2, 0), kakoune(2, 3)),
+                (position(3, 1), kakoune(2, 6)),
+                (position(4, 0), kakoune(3, 1)),
+            ] {
+                assert_eq!(lsp_position_to_kakoune(&lsp, &text, encoding), kak);
+                assert_eq!(kakoune_position_to_lsp(&kak, &text, encoding), lsp);
+            }
+            // Selecting a whole LSP line only selects up to its lone CR.
+            assert_eq!(
+                lsp_range_to_kakoune(&Range::new(position(2, 0), position(3, 0)), &text, encoding),
+                KakouneRange {
+                    start: kakoune(2, 3),
+                    end: kakoune(2, 4),
+                }
+            );
+            assert_eq!(
+                kakoune_range_to_lsp(
+                    &KakouneRange {
+                        start: kakoune(1, 1),
+                        end: kakoune(3, 1),
+                    },
+                    &text,
+                    encoding
+                ),
+                Range::new(position(0, 0), position(4, 0))
+            );
+        }
+        assert_eq!(lsp_line_to_kakoune(3, &text), 2);
+        assert_eq!(lsp_line_to_kakoune(4, &text), 3);
+        assert_eq!(lsp_line_to_kakoune(9, &text), 8);
+    }
 }
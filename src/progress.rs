@@ -1,5 +1,5 @@
 use crate::context::Context;
-use crate::types::EditorMeta;
+use crate::types::{EditorMeta, ServerId};
 use crate::util::editor_quote;
 use crate::{wcwidth, NotToEditor};
 use indoc::formatdoc;
@@ -24,18 +24,19 @@ pub fn work_done_progress_cancel(
 }
 
 pub fn work_done_progress_create(
+    server_id: ServerId,
     params: Params,
     ctx: &mut Context,
 ) -> Result<jsonrpc_core::Value, jsonrpc_core::Error> {
     let WorkDoneProgressCreateParams { token } = params
         .parse()
         .map_err(|_| jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::InvalidParams))?;
-    match ctx.work_done_progress.entry(token) {
+    match ctx.work_done_progress.entry((server_id, token)) {
         hash_map::Entry::Occupied(e) => {
             warn!(
                 ctx.to_editor(),
                 "Received duplicate ProgressToken '{:?}'",
-                e.key()
+                e.key().1
             );
         }
         hash_map::Entry::Vacant(e) => {
@@ -45,7 +46,7 @@ pub fn work_done_progress_create(
     Ok(jsonrpc_core::Value::Null)
 }
 
-pub fn dollar_progress(meta: EditorMeta, params: Params, ctx: &mut Context) {
+pub fn dollar_progress(server_id: ServerId, meta: EditorMeta, params: Params, ctx: &mut Context) {
     let params: ProgressParams = match params.parse() {
         Ok(params) => params,
         Err(err) => {
@@ -90,9 +91,10 @@ pub fn dollar_progress(meta: EditorMeta, params: Params, ctx: &mut Context) {
     }
 
     let token = &params.token;
+    let key = (server_id, params.token.clone());
     match params.value {
         ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(begin)) => {
-            match ctx.work_done_progress.get_mut(&params.token) {
+            match ctx.work_done_progress.get_mut(&key) {
                 Some(Some(_)) => {
                     warn!(
                         ctx.to_editor(),
@@ -128,7 +130,7 @@ pub fn dollar_progress(meta: EditorMeta, params: Params, ctx: &mut Context) {
                 return;
             }
             ctx.work_done_progress_report_timestamp = time::Instant::now();
-            match ctx.work_done_progress.get_mut(&params.token) {
+            match ctx.work_done_progress.get_mut(&key) {
                 Some(Some(progress)) => {
                     let command = handle_progress_command(
                         token,
@@ -160,7 +162,7 @@ pub fn dollar_progress(meta: EditorMeta, params: Params, ctx: &mut Context) {
             }
         }
         ProgressParamsValue::WorkDone(WorkDoneProgress::End(WorkDoneProgressEnd { message })) => {
-            match ctx.work_done_progress.remove(&params.token) {
+            match ctx.work_done_progress.remove(&key) {
                 Some(Some(WorkDoneProgressBegin { title, .. })) => {
                     let command =
                         handle_progress_command(token, &title, false, &message, &Some(100), true);
@@ -1,7 +1,8 @@
 use std::borrow::Cow;
 
 use itertools::Itertools;
-use jsonrpc_core::{Id, MethodCall};
+use jsonrpc_core::{ErrorCode, Id, MethodCall};
+use lsp_types::error_codes::REQUEST_CANCELLED;
 use lsp_types::{MessageActionItem, MessageType, ShowMessageRequestParams};
 use serde::Deserialize;
 
@@ -45,7 +46,16 @@ pub fn show_message_request_respond(
     params: MessageRequestResponse,
     ctx: &mut Context,
 ) {
-    for server_id in meta.servers {
+    let server_ids: Vec<ServerId> = ctx
+        .displayed_message_requests
+        .iter()
+        .filter(|(_, id)| *id == params.message_request_id)
+        .map(|(server_id, _)| *server_id)
+        .filter(|server_id| meta.servers.contains(server_id))
+        .collect();
+    for server_id in server_ids {
+        ctx.displayed_message_requests
+            .remove(&(server_id, params.message_request_id.clone()));
         let item = params
             .item
             .clone()
@@ -77,6 +87,8 @@ pub fn show_message_request_next(meta: EditorMeta, ctx: &mut Context) {
         }
     };
 
+    ctx.displayed_message_requests
+        .insert((server_id, id.clone()));
     let request_id = serde_json::ser::to_string(&id).unwrap();
     let request_id = editor_quote(&request_id);
 
@@ -113,6 +125,38 @@ pub fn show_message_request_next(meta: EditorMeta, ctx: &mut Context) {
     );
 }
 
+/// Drops a message request that the server no longer wants answered.
+pub fn cancel_message_request(meta: EditorMeta, server_id: ServerId, id: Id, ctx: &mut Context) {
+    let queued =
+        ctx.pending_message_requests
+            .iter()
+            .position(|(pending_id, pending_server_id, _)| {
+                *pending_server_id == server_id && *pending_id == id
+            });
+    if let Some(position) = queued {
+        ctx.pending_message_requests.remove(position);
+        update_modeline(meta, ctx);
+    } else if !ctx
+        .displayed_message_requests
+        .remove(&(server_id, id.clone()))
+    {
+        debug!(
+            ctx.to_editor(),
+            "Server {} canceled unknown request {:?}",
+            &ctx.server(server_id).name,
+            id
+        );
+        return;
+    }
+    ctx.reply(
+        server_id,
+        id,
+        Err(jsonrpc_core::Error::new(ErrorCode::ServerError(
+            REQUEST_CANCELLED,
+        ))),
+    );
+}
+
 /// Implements ShowMessage notification.
 pub fn show_message(
     meta: EditorMeta,
@@ -0,0 +1,182 @@
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+use lsp_types::{NumberOrString, TraceValue};
+
+use crate::capabilities::offset_encoding_name;
+use crate::context::{Context, ServerSettings};
+use crate::types::{EditorMeta, ServerId};
+use crate::util::editor_quote;
+
+/// Shows the state of all language servers in the *lsp-status* buffer.
+pub fn status(meta: EditorMeta, ctx: &mut Context) {
+    let mut content = String::new();
+    if ctx.language_servers.is_empty() {
+        content.push_str("No language servers are running\n");
+    }
+    for (&server_id, server) in &ctx.language_servers {
+        server_status(&mut content, ctx, server_id, server);
+        content.push('\n');
+    }
+    if !ctx.server_tombstones.is_empty() {
+        writeln!(
+            content,
+            "Disabled for this session: {}",
+            ctx.server_tombstones.iter().sorted().join(", ")
+        )
+        .unwrap();
+    }
+    let command = format!("lsp-show-status {}", editor_quote(&content));
+    ctx.exec(meta, command);
+}
+
+/// Shows request latencies in the *lsp-metrics* buffer.
+pub fn metrics(meta: EditorMeta, ctx: &mut Context) {
+    let command = format!("lsp-show-metrics {}", editor_quote(&ctx.metrics.table()));
+    ctx.exec(meta, command);
+}
+
+fn server_status(
+    content: &mut String,
+    ctx: &Context,
+    server_id: ServerId,
+    server: &ServerSettings,
+) {
+    let now = Instant::now();
+    let config = ctx.server_config(&server.start_meta, &server.name);
+    let command = config
+        .and_then(|config| config.command.clone())
+        .unwrap_or_else(|| server.name.clone());
+    let state = if ctx.server_tombstones.contains(&command) {
+        "disabled".to_string()
+    } else if let Some(restart_at) = server.restart_at {
+        format!(
+            "crashed, restarting in {}",
+            format_duration(restart_at.saturating_duration_since(now))
+        )
+    } else if server.capabilities.is_none() {
+        "initializing".to_string()
+    } else {
+        "running".to_string()
+    };
+    writeln!(content, "{} ({})", server.name, state).unwrap();
+    writeln!(content, "  roots: {}", server.roots.iter().join(", ")).unwrap();
+    match server.transport.pid() {
+        Some(pid) => writeln!(content, "  pid: {}", pid).unwrap(),
+        None => writeln!(content, "  pid: not started by kak-lsp").unwrap(),
+    }
+    writeln!(
+        content,
+        "  uptime: {}",
+        format_duration(now.duration_since(server.started_at))
+    )
+    .unwrap();
+    if server.crash_count != 0 {
+        writeln!(content, "  crashes in a row: {}", server.crash_count).unwrap();
+    }
+    writeln!(
+        content,
+        "  offset encoding: {}",
+        offset_encoding_name(server.offset_encoding)
+    )
+    .unwrap();
+    match ctx.server_trace(&server.name) {
+        TraceValue::Off => (),
+        TraceValue::Messages => writeln!(content, "  trace: messages").unwrap(),
+        TraceValue::Verbose => writeln!(content, "  trace: verbose").unwrap(),
+    }
+    if let Some(config) = config {
+        if config.command.is_some() || config.address.is_none() {
+            writeln!(
+                content,
+                "  command: {}",
+                Some(&command).into_iter().chain(&config.args).join(" ")
+            )
+            .unwrap();
+        }
+        if let Some(address) = &config.address {
+            writeln!(content, "  address: {}", address).unwrap();
+        }
+        if !config.envs.is_empty() {
+            writeln!(content, "  environment:").unwrap();
+            for (name, value) in config.envs.iter().sorted() {
+                writeln!(content, "    {}={}", name, value).unwrap();
+            }
+        }
+    }
+
+    let documents: Vec<_> = ctx
+        .documents
+        .iter()
+        .filter(|(_, document)| document.servers.contains(&server_id))
+        .map(|(buffile, document)| (buffile, document.version))
+        .sorted()
+        .collect();
+    writeln!(content, "  open documents: {}", documents.len()).unwrap();
+    for (buffile, version) in documents {
+        writeln!(content, "    {} (version {})", buffile, version).unwrap();
+    }
+
+    let requests: Vec<_> = ctx
+        .response_waitlist
+        .iter()
+        .filter(|(_, request)| request.server_id == server_id)
+        .sorted_by_key(|(_, request)| request.sent_at)
+        .collect();
+    writeln!(content, "  outstanding requests: {}", requests.len()).unwrap();
+    for (id, request) in requests {
+        writeln!(
+            content,
+            "    {} {} for {}{}",
+            match id {
+                jsonrpc_core::Id::Num(id) => id.to_string(),
+                jsonrpc_core::Id::Str(id) => id.clone(),
+                jsonrpc_core::Id::Null => "null".to_string(),
+            },
+            request.method,
+            format_duration(now.duration_since(request.sent_at)),
+            if request.canceled { " (canceled)" } else { "" },
+        )
+        .unwrap();
+    }
+
+    let progress: Vec<_> = ctx
+        .work_done_progress
+        .iter()
+        .filter(|((progress_server_id, _), _)| *progress_server_id == server_id)
+        .collect();
+    if !progress.is_empty() {
+        writeln!(content, "  progress:").unwrap();
+        for ((_, token), begin) in progress {
+            let token = match token {
+                NumberOrString::Number(token) => token.to_string(),
+                NumberOrString::String(token) => token.clone(),
+            };
+            match begin {
+                Some(begin) => {
+                    write!(content, "    {}: {}", token, begin.title).unwrap();
+                    if let Some(percentage) = begin.percentage {
+                        write!(content, " ({}%)", percentage).unwrap();
+                    }
+                    if let Some(message) = &begin.message {
+                        write!(content, ": {}", message).unwrap();
+                    }
+                    content.push('\n');
+                }
+                None => writeln!(content, "    {}: not started", token).unwrap(),
+            }
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs < 60 * 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {}m", secs / (60 * 60), secs / 60 % 60)
+    }
+}
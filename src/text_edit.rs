@@ -270,6 +270,10 @@ pub fn lsp_text_edits_to_kakoune<T: TextEditish<T>>(
         return None;
     }
 
+    // Kakoune strips the carriage returns of CRLF buffers (and adds them back when writing),
+    // so we need to do the same for inserted text.
+    let crlf = uses_crlf(text);
+
     // If the text edit just replaces the whole buffer, compute a minimal edit sequence to
     // maintain selections better.
     if client.is_some() && text_edits.len() == 1 {
@@ -298,7 +302,7 @@ pub fn lsp_text_edits_to_kakoune<T: TextEditish<T>>(
             let new_text = if new_text.ends_with('\n') {
                 Cow::Borrowed(new_text)
             } else {
-                Cow::Owned(new_text.to_string() + "\n")
+                Cow::Owned(new_text.to_string() + if crlf { "\r\n" } else { "\n" })
             };
             text_edits = minimal_edit_sequence(text, &Rope::from_str(&new_text));
             debug!(
@@ -412,11 +416,16 @@ pub fn lsp_text_edits_to_kakoune<T: TextEditish<T>>(
                     InsertBefore => 'i',
                     Replace => 'c',
                 };
+                let new_text = if crlf {
+                    Cow::Owned(new_text.replace("\r\n", "\n"))
+                } else {
+                    Cow::Borrowed(new_text)
+                };
                 formatdoc!(
                     "select {}
                      execute-keys \"{command}{}<esc>\"",
                     ForwardKakouneRange(*range),
-                    editor_escape_double_quotes(&escape_keys(new_text))
+                    editor_escape_double_quotes(&escape_keys(&new_text))
                 )
             },
         )
@@ -535,6 +544,13 @@ fn lsp_text_edit_to_kakoune<T: TextEditish<T>>(
     }
 }
 
+/// Whether the buffer has CRLF line endings. Like Kakoune, only look at the first line.
+fn uses_crlf(text: &Rope) -> bool {
+    let line = text.line(0);
+    let len = line.len_chars();
+    len >= 2 && line.char(len - 2) == '\r' && line.char(len - 1) == '\n'
+}
+
 fn minimal_edit_sequence<T: TextEditish<T>>(old: &Rope, new: &Rope) -> Vec<T> {
     let oldv = old.lines().collect::<Vec<_>>();
     let newv = new.lines().collect::<Vec<_>>();
@@ -1260,4 +1276,134 @@ mod tests {
         .to_string();
         assert_eq!(result, Some(expected));
     }
+
+    #[test]
+    pub fn apply_text_edits_to_rope_crlf() {
+        let text_edits = vec![edit(1, 0, 1, 3, "BAR"), edit(3, 0, 3, 1, "Q")];
+        let text = Rope::from_str("foo\r\nbar\u{c}baz\u{85}\r\nx\ry\r\n");
+        let updated_text =
+            apply_text_edits_to_rope(text, text_edits, OffsetEncoding::Utf8).unwrap();
+        let updated_text = String::from_utf8_lossy(&updated_text);
+        assert_eq!(updated_text, "foo\r\nBAR\u{c}baz\u{85}\r\nx\rQ\r\n");
+    }
+
+    #[test]
+    pub fn lsp_text_edits_to_kakoune_crlf() {
+        let text_edits = vec![edit(1, 0, 1, 3, "baz\r\nqux"), edit(2, 0, 2, 0, "// ")];
+        let buffer = Rope::from_str("foo\r\nbar\r\n\u{2028}end\r\n");
+        let result = lsp_text_edits_to_kakoune(
+            &mock_to_editor(),
+            &None,
+            text_edits,
+            &buffer,
+            OffsetEncoding::Utf8,
+        );
+        let expected = indoc!(
+            r#"select 3.1,3.1
+               execute-keys "i// <esc>"
+               select 2.1,2.3
+               execute-keys "cbaz
+               qux<esc>""#
+        )
+        .to_string();
+        assert_eq!(result, Some(expected));
+    }
+
+    #[test]
+    pub fn lsp_text_edits_to_kakoune_rewrite_whole_buffer_missing_eol_crlf() {
+        let buffer = Rope::from_str(
+            &indoc!(
+                r#"<head/>
+                       <body>
+                       asdf
+                   </body>
+                 "#
+            )
+            .replace('\n', "\r\n"),
+        );
+        let text_edits = vec![edit(
+            0,
+            0,
+            4,
+            0,
+            indoc!(
+                r#"<head/>
+
+                   <body>
+                           asdf
+                   </body>
+                 "#
+            )
+            .replace('\n', "\r\n")
+            .trim_end(),
+        )];
+        let result = lsp_text_edits_to_kakoune(
+            &mock_to_editor(),
+            &Some(ClientId("test_client".to_string())),
+            text_edits,
+            &buffer,
+            OffsetEncoding::Utf8,
+        );
+        let expected = indoc!(
+            r#"select 2.1,3.1000000
+               execute-keys "c
+               <lt>body>
+                       asdf
+               <esc>""#
+        )
+        .to_string();
+        assert_eq!(result, Some(expected));
+    }
+
+    #[test]
+    pub fn lsp_text_edits_to_kakoune_rewrite_whole_buffer_text_edit_missing_eol_crlf() {
+        let buffer = Rope::from_str(
+            &indoc!(
+                r#"<body>
+                                   asdf
+
+                           asdf
+                           asdf
+                           asdf
+                           asdf
+                           sadf
+                   </body>
+                 "#
+            )
+            .replace('\n', "\r\n"),
+        );
+        let text_edits = vec![edit(
+            0,
+            0,
+            9,
+            0,
+            indoc!(
+                r#"<body>
+                           asdf
+
+                           asdf
+                           asdf
+                           asdf
+                           asdf
+                           sadf
+                   </body>"#
+            )
+            .replace('\n', "\r\n")
+            .trim_end(),
+        )];
+        let result = lsp_text_edits_to_kakoune(
+            &mock_to_editor(),
+            &Some(ClientId("test_client".to_string())),
+            text_edits,
+            &buffer,
+            OffsetEncoding::Utf8,
+        );
+        let expected = indoc!(
+            r#"select 2.1,2.1000000
+               execute-keys "c        asdf
+               <esc>""#
+        )
+        .to_string();
+        assert_eq!(result, Some(expected));
+    }
 }
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     path::{Path, PathBuf},
     time::Duration,
 };
@@ -7,6 +7,7 @@ use std::{
 use crate::thread_worker::Worker;
 use crate::types::*;
 use crate::util::file_path_to_uri;
+use crate::util::is_scratch_buffer;
 use crate::util::uri_to_file_path;
 use crate::{context::*, editor_transport::ToEditorSender};
 use crossbeam_channel::{Receiver, Sender};
@@ -26,9 +27,16 @@ pub fn text_document_did_open(
     params: TextDocumentDidOpenParams,
     ctx: &mut Context,
 ) {
+    if is_virtual_document(&meta.buffile, ctx) {
+        return;
+    }
     let document = Document {
         version: meta.version,
         text: Rope::from_str(&params.draft),
+        content_provider: None,
+        history: VecDeque::new(),
+        language_id: meta.language_id.clone(),
+        servers: meta.servers.clone(),
     };
     ctx.documents.insert(meta.buffile.clone(), document);
 
@@ -45,11 +53,36 @@ pub fn text_document_did_open(
     }
 }
 
+/// Open the documents of a restarted server again, with their current contents.
+pub fn reopen_documents(server_id: ServerId, ctx: &mut Context) {
+    let params: Vec<_> = ctx
+        .documents
+        .iter()
+        .filter(|(_, document)| {
+            document.content_provider.is_none() && document.servers.contains(&server_id)
+        })
+        .map(|(buffile, document)| DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: file_path_to_uri(buffile),
+                language_id: document.language_id.clone(),
+                version: document.version,
+                text: document.text.to_string(),
+            },
+        })
+        .collect();
+    for params in params {
+        ctx.notify::<DidOpenTextDocument>(server_id, params);
+    }
+}
+
 pub fn text_document_did_change(
     meta: EditorMeta,
     params: TextDocumentDidChangeParams,
     ctx: &mut Context,
 ) {
+    if is_virtual_document(&meta.buffile, ctx) {
+        return;
+    }
     let uri = file_path_to_uri(&meta.buffile);
     let version = meta.version;
     let old_version = ctx
@@ -60,9 +93,29 @@ pub fn text_document_did_change(
     if old_version >= version {
         return;
     }
+    let text = Rope::from_str(&params.draft);
+    let history = match ctx.documents.remove(&meta.buffile) {
+        Some(Document {
+            version,
+            text: old_text,
+            mut history,
+            ..
+        }) => {
+            if history.len() == DOCUMENT_HISTORY_LENGTH {
+                history.pop_front();
+            }
+            history.push_back((version, line_changes(&old_text, &text)));
+            history
+        }
+        None => VecDeque::new(),
+    };
     let document = Document {
         version,
-        text: Rope::from_str(&params.draft),
+        text,
+        content_provider: None,
+        history,
+        language_id: meta.language_id.clone(),
+        servers: meta.servers.clone(),
     };
 
     // Resets metadata for buffer.
@@ -85,8 +138,152 @@ pub fn text_document_did_change(
     }
 }
 
+/// The lines that differ between two versions of a document.
+pub fn line_changes(old_text: &Rope, text: &Rope) -> Vec<LineChange> {
+    struct Changes {
+        // The lines before the ones we diff.
+        offset: usize,
+        changes: Vec<LineChange>,
+    }
+    impl Changes {
+        fn push(&mut self, mut change: LineChange) {
+            change.start += self.offset;
+            // Join the lines deleted and inserted at the same place into one replacement.
+            match self.changes.last_mut() {
+                Some(last) if last.start + last.old_len == change.start => {
+                    last.old_len += change.old_len;
+                    last.new_len += change.new_len;
+                }
+                _ => self.changes.push(change),
+            }
+        }
+    }
+    impl diffs::Diff for Changes {
+        type Error = ();
+        fn delete(&mut self, old: usize, len: usize, _new: usize) -> Result<(), ()> {
+            self.push(LineChange {
+                start: old,
+                old_len: len,
+                new_len: 0,
+            });
+            Ok(())
+        }
+        fn insert(&mut self, old: usize, _new: usize, new_len: usize) -> Result<(), ()> {
+            self.push(LineChange {
+                start: old,
+                old_len: 0,
+                new_len,
+            });
+            Ok(())
+        }
+    }
+    // Edits are usually confined to a few lines, so only diff the lines between the common
+    // prefix and suffix, which are cheap to find by comparing whole chunks.
+    let prefix = common_len(old_text.chunks(), text.chunks(), false);
+    let max_suffix = old_text.len_bytes().min(text.len_bytes()) - prefix;
+    let suffix = common_len(
+        old_text.chunks_at_byte(old_text.len_bytes()).0.reversed(),
+        text.chunks_at_byte(text.len_bytes()).0.reversed(),
+        true,
+    )
+    .min(max_suffix);
+    // Whether the line break at the edge of the prefix or suffix is a CRLF can depend on
+    // the differing bytes, so include the lines on both edges in the diff.
+    let first = old_text.byte_to_line(prefix).min(text.byte_to_line(prefix));
+    let lines_after =
+        |text: &Rope| text.len_lines() - 1 - text.byte_to_line(text.len_bytes() - suffix);
+    let unchanged_tail = lines_after(old_text).min(lines_after(text));
+    let old_lines: Vec<_> = old_text
+        .lines_at(first)
+        .take(old_text.len_lines() - unchanged_tail - first)
+        .collect();
+    let lines: Vec<_> = text
+        .lines_at(first)
+        .take(text.len_lines() - unchanged_tail - first)
+        .collect();
+    let mut changes = Changes {
+        offset: first,
+        changes: vec![],
+    };
+    let _ = diffs::myers::diff(
+        &mut changes,
+        &old_lines,
+        0,
+        old_lines.len(),
+        &lines,
+        0,
+        lines.len(),
+    );
+    changes.changes
+}
+
+/// The number of bytes at the start of both texts that are the same, or at the end when the
+/// chunks are reversed.
+fn common_len<'a>(
+    mut old_chunks: impl Iterator<Item = &'a str>,
+    mut chunks: impl Iterator<Item = &'a str>,
+    reversed: bool,
+) -> usize {
+    let mut len = 0;
+    let (mut old_chunk, mut chunk): (&[u8], &[u8]) = (&[], &[]);
+    loop {
+        if old_chunk.is_empty() {
+            match old_chunks.next() {
+                Some(next) => old_chunk = next.as_bytes(),
+                None => return len,
+            }
+            continue;
+        }
+        if chunk.is_empty() {
+            match chunks.next() {
+                Some(next) => chunk = next.as_bytes(),
+                None => return len,
+            }
+            continue;
+        }
+        let n = old_chunk.len().min(chunk.len());
+        let (old_part, part, old_rest, rest) = if reversed {
+            let (old_rest, old_part) = old_chunk.split_at(old_chunk.len() - n);
+            let (rest, part) = chunk.split_at(chunk.len() - n);
+            (old_part, part, old_rest, rest)
+        } else {
+            let (old_part, old_rest) = old_chunk.split_at(n);
+            let (part, rest) = chunk.split_at(n);
+            (old_part, part, old_rest, rest)
+        };
+        if old_part != part {
+            let same = |(a, b): &(&u8, &u8)| a == b;
+            return len
+                + if reversed {
+                    old_part
+                        .iter()
+                        .rev()
+                        .zip(part.iter().rev())
+                        .take_while(same)
+                        .count()
+                } else {
+                    old_part.iter().zip(part).take_while(same).count()
+                };
+        }
+        len += n;
+        old_chunk = old_rest;
+        chunk = rest;
+    }
+}
+
 pub fn text_document_did_close(meta: EditorMeta, ctx: &mut Context) {
-    ctx.documents.remove(&meta.buffile);
+    let document = ctx.documents.remove(&meta.buffile);
+    if document.is_some_and(|doc| doc.content_provider.is_some()) {
+        // The server never saw a textDocument/didOpen for this one.
+        return;
+    }
+    if is_scratch_buffer(&meta.buffile) {
+        // Once deleted, a scratch buffer is gone for good.
+        ctx.diagnostics.remove(&meta.buffile);
+        ctx.code_lenses.remove(&meta.buffile);
+        ctx.code_lenses_resolve_attempted.remove(&meta.buffile);
+        ctx.inlay_hints.remove(&meta.buffile);
+    }
     let uri = file_path_to_uri(&meta.buffile);
     let params = DidCloseTextDocumentParams {
         text_document: TextDocumentIdentifier { uri },
@@ -125,6 +322,13 @@ pub fn text_document_did_save(meta: EditorMeta, ctx: &mut Context) {
     }
 }
 
+/// Virtual documents are read-only and never synced to the server.
+fn is_virtual_document(buffile: &str, ctx: &Context) -> bool {
+    ctx.documents
+        .get(buffile)
+        .is_some_and(|doc| doc.content_provider.is_some())
+}
+
 pub fn spawn_file_watcher(
     to_editor: ToEditorSender,
     log_path: &'static Option<PathBuf>,
@@ -315,3 +519,96 @@ pub fn register_workspace_did_change_watched_files(
             .push(CompiledFileSystemWatcher { kind, pattern });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_changes_between_versions() {
+        let old_text = Rope::from_str("a\nb\nc\nd\ne\n");
+        let text = Rope::from_str("a\nB\nc\nx\ny\nd\n");
+        assert_eq!(
+            line_changes(&old_text, &text),
+            [
+                LineChange {
+                    start: 1,
+                    old_len: 1,
+                    new_len: 1,
+                },
+                LineChange {
+                    start: 3,
+                    old_len: 0,
+                    new_len: 2,
+                },
+                LineChange {
+                    start: 4,
+                    old_len: 1,
+                    new_len: 0,
+                },
+            ]
+        );
+        assert_eq!(line_changes(&text, &text), []);
+    }
+
+    /// Check that the lines outside of the changes are the same in both texts.
+    fn assert_lines_match(old_text: &Rope, text: &Rope, changes: &[LineChange]) {
+        let (mut old_line, mut line) = (0, 0);
+        for change in changes.iter().chain(Some(&LineChange {
+            start: old_text.len_lines(),
+            old_len: 0,
+            new_len: 0,
+        })) {
+            while old_line < change.start {
+                assert_eq!(old_text.line(old_line), text.line(line));
+                old_line += 1;
+                line += 1;
+            }
+            old_line += change.old_len;
+            line += change.new_len;
+        }
+        assert_eq!(line, text.len_lines());
+    }
+
+    #[test]
+    fn line_changes_in_large_text() {
+        let lines: String = (0..5000).map(|i| format!("line {i}\n")).collect();
+        let old_text = Rope::from_str(&lines);
+        let mut text = old_text.clone();
+        let start = text.line_to_char(2500);
+        text.insert(start, "new\n");
+        text.remove(text.line_to_char(4000)..text.line_to_char(4001));
+        let changes = line_changes(&old_text, &text);
+        assert_eq!(
+            changes,
+            [
+                LineChange {
+                    start: 2500,
+                    old_len: 0,
+                    new_len: 1,
+                },
+                LineChange {
+                    start: 3999,
+                    old_len: 1,
+                    new_len: 0,
+                },
+            ]
+        );
+        assert_lines_match(&old_text, &text, &changes);
+    }
+
+    #[test]
+    fn line_changes_at_line_break_edges() {
+        for (old_text, text) in [
+            ("a\r\nb\n", "a\rb\n"),
+            ("a\rb\n", "a\r\nb\n"),
+            ("a\nb", "a\nb\n"),
+            ("a\n", ""),
+            ("", "a"),
+            ("x\r\n", "x\r\r\n"),
+        ] {
+            let (old_text, text) = (Rope::from_str(old_text), Rope::from_str(text));
+            assert_lines_match(&old_text, &text, &line_changes(&old_text, &text));
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! A JSON Lines trace of the messages exchanged with language servers and the editor.
+//!
+//! Language server messages use the JSON trace format of VS Code (`"trace.server": {"format": "json"}`),
+//! so the trace can be loaded into LSP inspector tools. Editor requests are recorded as the tokens
+//! and buffer contents kak-lsp read from its fifos, so that `kak-lsp replay` can parse them again.
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonrpc_core::Call;
+use serde::Serialize;
+
+use crate::types::{ServerMessage, ServerName};
+
+static TRACE: Mutex<Option<LineWriter<File>>> = Mutex::new(None);
+
+pub const EDITOR_REQUEST: &str = "editor-request";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceEntry {
+    #[serde(default, rename = "isLSPMessage")]
+    pub is_lsp_message: bool,
+    /// Direction and kind of the message, for example "send-request" or "receive-notification".
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<serde_json::Value>,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<ServerName>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub texts: Vec<String>,
+}
+
+/// Start writing the trace to the given file, replacing its contents.
+pub fn open(path: &Path) -> io::Result<()> {
+    let file = File::create(path)?;
+    *TRACE.lock().unwrap() = Some(LineWriter::new(file));
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    TRACE.lock().unwrap().is_some()
+}
+
+/// Read a trace written by a previous session.
+pub fn load(path: &Path) -> Result<Vec<TraceEntry>, String> {
+    let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|(i, line)| {
+            let line = line.map_err(|err| format!("{}: {}", path.display(), err))?;
+            serde_json::from_str(&line)
+                .map_err(|err| format!("{}:{}: {}", path.display(), i + 1, err))
+        })
+        .collect()
+}
+
+/// The trace type of a message, as in "send-request".
+pub fn message_kind(direction: &str, message: &ServerMessage) -> String {
+    let kind = match message {
+        ServerMessage::Request(Call::Notification(_)) => "notification",
+        ServerMessage::Request(_) => "request",
+        ServerMessage::Response(_) => "response",
+    };
+    format!("{}-{}", direction, kind)
+}
+
+/// Record a message that was sent to ("send") or received from ("receive") a language server.
+pub fn server_message(
+    server_name: &ServerName,
+    direction: &str,
+    message: &ServerMessage,
+    text: &str,
+) -> io::Result<()> {
+    write(TraceEntry {
+        is_lsp_message: true,
+        kind: message_kind(direction, message),
+        message: Some(serde_json::from_str(text)?),
+        server: Some(server_name.clone()),
+        ..Default::default()
+    })
+}
+
+/// Record an editor request, as the tokens read from the command fifo and the buffer contents
+/// read from the other fifo.
+pub fn editor_request(tokens: Vec<String>, texts: Vec<String>) -> io::Result<()> {
+    write(TraceEntry {
+        kind: EDITOR_REQUEST.to_string(),
+        tokens,
+        texts,
+        ..Default::default()
+    })
+}
+
+/// Write an entry to the trace. After a failure, tracing stops, so the error is only reported once.
+fn write(mut entry: TraceEntry) -> io::Result<()> {
+    let mut trace = TRACE.lock().unwrap();
+    let Some(writer) = trace.as_mut() else {
+        return Ok(());
+    };
+    entry.timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let result = serde_json::to_writer(&mut *writer, &entry)
+        .map_err(io::Error::from)
+        .and_then(|()| writer.write_all(b"\n"));
+    if result.is_err() {
+        *trace = None;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpc_core::{Id, MethodCall, Params, Version};
+
+    #[test]
+    fn server_message_kinds() {
+        let request = ServerMessage::Request(Call::MethodCall(MethodCall {
+            jsonrpc: Some(Version::V2),
+            method: "shutdown".to_string(),
+            params: Params::None,
+            id: Id::Num(1),
+        }));
+        assert_eq!(message_kind("send", &request), "send-request");
+        let response: ServerMessage = ServerMessage::Response(
+            serde_json::from_str(r#"{"jsonrpc":"2.0","result":null,"id":1}"#).unwrap(),
+        );
+        assert_eq!(message_kind("receive", &response), "receive-response");
+    }
+
+    #[test]
+    fn entry_format() {
+        let entry = TraceEntry {
+            is_lsp_message: true,
+            kind: "receive-notification".to_string(),
+            message: Some(serde_json::json!({"jsonrpc": "2.0", "method": "initialized"})),
+            timestamp: 1700000000000,
+            server: Some("clangd".to_string()),
+            ..Default::default()
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            line,
+            r#"{"isLSPMessage":true,"type":"receive-notification","message":{"jsonrpc":"2.0","method":"initialized"},"timestamp":1700000000000,"server":"clangd"}"#
+        );
+        let entry: TraceEntry = serde_json::from_str(&line).unwrap();
+        assert!(entry.tokens.is_empty());
+    }
+}
@@ -2,6 +2,7 @@ use jsonrpc_core::{Call, Output, Params};
 use libc::{ENXIO, O_NONBLOCK};
 use lsp_types::{
     CodeActionKind, DiagnosticSeverity, FormattingOptions, Position, SemanticTokenModifier,
+    TraceValue,
 };
 use serde::de::{MapAccess, SeqAccess, Visitor};
 use serde::{de::Error as SerdeError, Deserialize, Deserializer, Serialize};
@@ -13,6 +14,7 @@ use std::fmt::Display;
 use std::io::{Error, Write};
 use std::ops::Deref;
 use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
 use std::time::Duration;
 use std::{fs, io};
 
@@ -55,7 +57,7 @@ pub struct DynamicConfig {
     pub language_server: HashMap<ServerName, DynamicLanguageServerConfig>,
 }
 
-#[derive(Clone, Default, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct ServerConfig {
     #[deprecated]
     #[allow(unused)]
@@ -63,6 +65,86 @@ pub struct ServerConfig {
     session: String,
     #[serde(default)]
     pub timeout: u64,
+    /// Seconds to give language servers to shut down and exit, before terminating them.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Warn about requests that take longer than this many milliseconds. 0 disables the warning.
+    #[serde(default)]
+    pub slow_request_threshold: u64,
+    /// Milliseconds to wait for the response to a request, per method, with "*" for other methods.
+    #[serde(default)]
+    pub request_timeouts: HashMap<String, u64>,
+    /// Where to write the request latencies on exit.
+    #[serde(default)]
+    pub metrics_file: Option<PathBuf>,
+    /// Where to write a trace of all messages to language servers and from the editor.
+    #[serde(default)]
+    pub trace_file: Option<PathBuf>,
+    /// How much language servers should report about their execution via `$/logTrace`.
+    #[serde(default)]
+    pub server_trace: HashMap<ServerName, TraceValue>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        #[allow(deprecated)]
+        ServerConfig {
+            session: String::new(),
+            timeout: 0,
+            shutdown_timeout: default_shutdown_timeout(),
+            slow_request_threshold: 0,
+            request_timeouts: HashMap::new(),
+            metrics_file: None,
+            trace_file: None,
+            server_trace: HashMap::new(),
+        }
+    }
+}
+
+fn default_shutdown_timeout() -> u64 {
+    3
+}
+
+/// Parse trace levels like "rust-analyzer=verbose clangd=messages".
+pub fn parse_server_trace(value: &str) -> Result<HashMap<ServerName, TraceValue>, String> {
+    value
+        .split_whitespace()
+        .map(|entry| {
+            let entry = entry.trim_matches('\'');
+            let (server_name, level) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected <server>=<level>, got '{}'", entry))?;
+            let level = match level {
+                "off" => TraceValue::Off,
+                "messages" => TraceValue::Messages,
+                "verbose" => TraceValue::Verbose,
+                _ => {
+                    return Err(format!(
+                        "invalid trace level '{}', expected off, messages or verbose",
+                        level
+                    ))
+                }
+            };
+            Ok((server_name.to_string(), level))
+        })
+        .collect()
+}
+
+/// Parse request timeouts like "textDocument/formatting=5000 *=30000".
+pub fn parse_request_timeouts(value: &str) -> Result<HashMap<String, u64>, String> {
+    value
+        .split_whitespace()
+        .map(|entry| {
+            let entry = entry.trim_matches('\'');
+            let (method, timeout) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected <method>=<milliseconds>, got '{}'", entry))?;
+            let timeout = timeout
+                .parse()
+                .map_err(|err| format!("invalid timeout for {}: {}", method, err))?;
+            Ok((method.to_string(), timeout))
+        })
+        .collect()
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -82,6 +164,8 @@ pub struct LanguageServerConfig {
     pub command: Option<String>,
     #[serde(default)]
     pub args: Vec<String>,
+    /// Talk to the server over a socket instead of stdio: "tcp://host:port" or "unix:/path".
+    pub address: Option<String>,
     #[serde(default)]
     pub envs: HashMap<String, String>,
     pub settings_section: Option<String>,
@@ -91,12 +175,80 @@ pub struct LanguageServerConfig {
     #[serde(default)]
     pub symbol_kinds: HashMap<String, String>,
     pub experimental: Option<Value>,
+    /// Only show inlay hints of these kinds. All kinds are shown if unset.
+    pub inlay_hint_kinds: Option<Vec<InlayHintKindFilter>>,
+    #[serde(default)]
+    pub diagnostic_filter: DiagnosticFilter,
     // This does nothing, but is kept so we can still parse old configs.
     #[allow(dead_code)]
     workaround_server_sends_plaintext_labeled_as_markdown: Option<bool>,
     pub workaround_eslint: Option<bool>,
 }
 
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InlayHintKindFilter {
+    Type,
+    Parameter,
+    /// Hints without a kind.
+    Other,
+}
+
+/// Drops or downgrades a server's diagnostics before they are stored.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DiagnosticFilter {
+    /// Drop diagnostics that are less severe than this, after applying the rules.
+    pub min_severity: Option<DiagnosticLevel>,
+    /// The first matching rule applies.
+    #[serde(default)]
+    pub rules: Vec<DiagnosticFilterRule>,
+}
+
+/// Matches diagnostics where all given fields match.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DiagnosticFilterRule {
+    pub source: Option<String>,
+    pub code: Option<String>,
+    /// Regex searched in the message.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub message: Option<regex::Regex>,
+    /// Downgrade matching diagnostics to this severity. They are dropped if unset.
+    pub severity: Option<DiagnosticLevel>,
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<regex::Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    regex::Regex::new(&pattern)
+        .map(Some)
+        .map_err(SerdeError::custom)
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticLevel {
+    Error,
+    Warning,
+    #[serde(alias = "information")]
+    Info,
+    Hint,
+}
+
+impl From<DiagnosticLevel> for DiagnosticSeverity {
+    fn from(level: DiagnosticLevel) -> Self {
+        match level {
+            DiagnosticLevel::Error => DiagnosticSeverity::ERROR,
+            DiagnosticLevel::Warning => DiagnosticSeverity::WARNING,
+            DiagnosticLevel::Info => DiagnosticSeverity::INFORMATION,
+            DiagnosticLevel::Hint => DiagnosticSeverity::HINT,
+        }
+    }
+}
+
 #[derive(Clone, Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct DynamicLanguageServerConfig {
@@ -269,6 +421,10 @@ impl ResponseFifo {
     pub fn new(fifo: String) -> Self {
         Self(Some(fifo))
     }
+    /// Drops the fifo without writing to it, for when nobody reads from it.
+    pub fn disarm(mut self) {
+        self.0 = None;
+    }
     pub fn write(&mut self, command: &str) {
         let fifo = self.0.take().unwrap();
         let mut opts = fs::OpenOptions::new();
@@ -588,7 +744,7 @@ impl Display for BackwardKakouneRange {
 }
 
 /// Represents how language server interprets LSP's `Position.character`
-#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OffsetEncoding {
     /// UTF-8 code units aka bytes
     #[serde(rename = "utf-8")]
@@ -597,6 +753,9 @@ pub enum OffsetEncoding {
     #[serde(rename = "utf-16")]
     #[default]
     Utf16,
+    /// UTF-32 code units aka Unicode code points
+    #[serde(rename = "utf-32")]
+    Utf32,
 }
 
 // An intermediate representation of the diagnostics on a line, for use with inlay diagnostics
@@ -607,3 +766,22 @@ pub struct LineDiagnostics<'a> {
     pub text_face: &'static str,
     pub text_severity: Option<DiagnosticSeverity>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_timeouts_by_method() {
+        assert_eq!(
+            parse_request_timeouts("textDocument/formatting=5000 '*=30000'"),
+            Ok(HashMap::from([
+                ("textDocument/formatting".to_string(), 5000),
+                ("*".to_string(), 30000),
+            ]))
+        );
+        assert_eq!(parse_request_timeouts(""), Ok(HashMap::new()));
+        assert!(parse_request_timeouts("textDocument/formatting").is_err());
+        assert!(parse_request_timeouts("textDocument/formatting=soon").is_err());
+    }
+}
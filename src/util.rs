@@ -132,16 +132,82 @@ where
 }
 
 /// Convert a filesystem path to a file:// URI.
+///
+/// Buffers that hold virtual documents are named after their URI, so those names are returned
+/// as-is. Other scratch buffers get an untitled: URI.
 pub fn file_path_to_uri(path: &str) -> Uri {
+    if is_scratch_buffer(path) {
+        if let Some(uri) = Uri::from_str(path).ok().filter(is_virtual_uri) {
+            return uri;
+        }
+        let name: String = url::form_urlencoded::byte_serialize(path.as_bytes()).collect();
+        return Uri::from_str(&format!("untitled:{}", name.replace('+', "%20"))).unwrap();
+    }
     let url = url::Url::from_file_path(path).unwrap();
     let s = url.as_str().replace('[', "%5B").replace(']', "%5D");
     Uri::from_str(&s).unwrap()
 }
 
 /// Parse a file:// URI and return the filesystem path.
+///
+/// For untitled: URIs, this returns the name of the scratch buffer. Other URIs are returned
+/// unchanged, because that is how we name buffers of virtual documents.
 pub fn uri_to_file_path(uri: &Uri) -> PathBuf {
+    if is_untitled_uri(uri) {
+        return PathBuf::from(uri.path().as_estr().decode().into_string_lossy().as_ref());
+    }
+    if is_virtual_uri(uri) {
+        return PathBuf::from(uri.as_str());
+    }
     url::Url::parse(uri.as_str())
         .ok()
         .and_then(|url| url.to_file_path().ok())
         .unwrap_or_else(|| PathBuf::from(uri.path().as_str()))
 }
+
+/// Whether the URI refers to something other than a file on disk, for example a class file
+/// inside a jar (jdt://) or a remote module (deno:/).
+pub fn is_virtual_uri(uri: &Uri) -> bool {
+    uri.scheme()
+        .is_some_and(|scheme| !scheme.as_str().eq_ignore_ascii_case("file"))
+}
+
+/// Whether the URI refers to a scratch buffer, see `file_path_to_uri`.
+pub fn is_untitled_uri(uri: &Uri) -> bool {
+    uri.scheme()
+        .is_some_and(|scheme| scheme.as_str().eq_ignore_ascii_case("untitled"))
+}
+
+/// Whether the buffer is not backed by a file.
+pub fn is_scratch_buffer(buffile: &str) -> bool {
+    !buffile.is_empty() && !buffile.starts_with('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uri_round_trip() {
+        for (path, uri) in [
+            ("/home/user/src/main.rs", "file:///home/user/src/main.rs"),
+            ("/tmp/[id]/page.tsx", "file:///tmp/%5Bid%5D/page.tsx"),
+            ("*scratch*", "untitled:*scratch*"),
+            ("*my notes*", "untitled:*my%20notes*"),
+            (
+                "jdt://contents/rt.jar/java.lang/String.class?=x",
+                "jdt://contents/rt.jar/java.lang/String.class?=x",
+            ),
+            (
+                "deno:/https/deno.land/std/path/mod.ts",
+                "deno:/https/deno.land/std/path/mod.ts",
+            ),
+        ] {
+            assert_eq!(file_path_to_uri(path).as_str(), uri);
+            assert_eq!(
+                uri_to_file_path(&Uri::from_str(uri).unwrap()),
+                PathBuf::from(path)
+            );
+        }
+    }
+}